@@ -0,0 +1,515 @@
+use std::time::Duration;
+
+use actix_web::{
+    web::{self, Json},
+    HttpRequest, HttpResponse, Responder, Scope,
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use utoipa::{OpenApi, ToSchema};
+
+use crate::{
+    auth::{self, Auth},
+    backend::{
+        MediaSource, MediaType, PlaybackCandidate, StorageHandle, StorageKind, StorageReport,
+    },
+    errors::{ErrorBody, ErrorReport, HomeRadioError},
+    events::{Event, EventBus},
+    logging::{LogControl, LogLevel, LogRecord, RecordQuery},
+    media_service::{
+        Current, Incident, IncidentKind, Metadata, Mismatch, MismatchKind, PlaybackState,
+        PlaybackTarget, PlayerHandle, PlayerSnapshot, ReconcileHandle, ReconcileReport,
+        StatusHandle, StreamInfo, SupervisorHandle, SupervisorReport, WatchdogHandle,
+        WatchdogReport,
+    },
+    metrics::Metrics,
+};
+
+/// Where the routes of this version are mounted.
+pub const PREFIX: &str = "/api/v1";
+
+/// How long `/readyz` waits for the player to answer.
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The OpenAPI document of the routes below `/api/v1` and the health checks, served at `/api/v1/openapi.json`.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "home-radio", description = "Controls the radio player and its stored media sources."),
+    paths(
+        list_sources,
+        add_source,
+        get_playback,
+        start_playback,
+        stop_playback,
+        get_volume,
+        set_volume,
+        get_status,
+        get_events,
+        get_openapi,
+        healthz,
+        readyz,
+        get_metrics,
+        login,
+        logout,
+        get_log_filter,
+        set_log_filter,
+        get_logs,
+    ),
+    components(schemas(
+        MediaSource,
+        MediaType,
+        PlaybackCandidate,
+        Playback,
+        PlaybackTarget,
+        Current,
+        Volume,
+        StatusResponse,
+        PlayerSnapshot,
+        PlaybackState,
+        Metadata,
+        StreamInfo,
+        SupervisorReport,
+        WatchdogReport,
+        Incident,
+        IncidentKind,
+        ReconcileReport,
+        Mismatch,
+        MismatchKind,
+        Event,
+        ErrorBody,
+        ErrorReport,
+        StorageReport,
+        StorageKind,
+        Readiness,
+        LoginRequest,
+        LoginResponse,
+        LogFilter,
+        LogRecord,
+        LogLevel,
+        auth::Scope,
+    )),
+    tags(
+        (name = "sources", description = "stored media sources"),
+        (name = "playback", description = "what is playing and how loud"),
+        (name = "diagnostics"),
+        (name = "auth", description = "only needed if authentication is enabled, integrations send `Authorization: Bearer <token>` instead"),
+    )
+)]
+pub struct ApiDoc;
+
+pub fn scope() -> Scope {
+    web::scope(PREFIX)
+        .route("/sources", web::get().to(list_sources))
+        .route("/sources", web::post().to(add_source))
+        .route("/playback", web::get().to(get_playback))
+        .route("/playback/start", web::post().to(start_playback))
+        .route("/playback/stop", web::post().to(stop_playback))
+        .route("/volume", web::get().to(get_volume))
+        .route("/volume", web::put().to(set_volume))
+        .route("/status", web::get().to(get_status))
+        .route("/events", web::get().to(get_events))
+        .route("/openapi.json", web::get().to(get_openapi))
+        .route("/login", web::post().to(login))
+        .route("/logout", web::post().to(logout))
+        .route("/logging", web::get().to(get_log_filter))
+        .route("/logging", web::put().to(set_log_filter))
+        // clients of the api expect an error body, not the empty 404 of actix
+        .default_service(web::to(unknown_endpoint))
+}
+
+async fn unknown_endpoint(req: HttpRequest) -> Result<HttpResponse, HomeRadioError> {
+    Err(HomeRadioError::UnknownEndpoint(format!(
+        "{} {}",
+        req.method(),
+        req.path()
+    )))
+}
+
+/// The stored current source and the link that is actually playing.
+#[derive(Serialize, ToSchema)]
+pub struct Playback {
+    /// `null` while stopped
+    pub current: Option<Current>,
+    /// the link the player is on, may be an alternate or a fallback of `current`
+    pub active: Option<PlaybackCandidate>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct Volume {
+    /// in the units of the player, 256 is 100%
+    pub volume: u16,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct StatusResponse {
+    /// `null` while the player can't be reached
+    player: Option<PlayerSnapshot>,
+    vlc: SupervisorReport,
+    watchdog: WatchdogReport,
+    reconciler: ReconcileReport,
+    storage: StorageReport,
+    /// the latest of the errors of vlc, playback and storage
+    last_error: Option<ErrorReport>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Readiness {
+    ready: bool,
+    vlc_reachable: bool,
+    state_dir_writable: bool,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/sources",
+    tag = "sources",
+    responses(
+        (status = 200, description = "all stored media sources", body = [MediaSource]),
+        (status = 503, description = "the player service is not running", body = ErrorBody),
+    )
+)]
+pub async fn list_sources(
+    player: web::Data<PlayerHandle>,
+) -> Result<Json<Vec<MediaSource>>, HomeRadioError> {
+    Ok(Json(player.media_sources().await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/sources",
+    tag = "sources",
+    request_body = MediaSource,
+    responses(
+        (status = 201, description = "the stored source with its id", body = MediaSource),
+        (status = 400, description = "the body is not a media source", body = ErrorBody),
+        (status = 404, description = "the source has an id that doesn't exist", body = ErrorBody),
+    )
+)]
+/// A source with an id replaces the source with that id, one without an id
+/// replaces the source with the same name or is added with a new id.
+pub async fn add_source(
+    player: web::Data<PlayerHandle>,
+    body: Json<MediaSource>,
+) -> Result<HttpResponse, HomeRadioError> {
+    let source = player.add_media_source(body.0).await?;
+    Ok(HttpResponse::Created().json(source))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/playback",
+    tag = "playback",
+    responses((status = 200, body = Playback))
+)]
+pub async fn get_playback(
+    player: web::Data<PlayerHandle>,
+    watchdog: web::Data<WatchdogHandle>,
+) -> Result<Json<Playback>, HomeRadioError> {
+    playback(&player, &watchdog).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/playback/start",
+    tag = "playback",
+    request_body(content = PlaybackTarget, description = "exactly one of the fields"),
+    responses(
+        (status = 200, description = "the player is playing", body = Playback),
+        (status = 400, description = "the body is not a playback target", body = ErrorBody),
+        (status = 403, description = "the link is not stored and ad-hoc links are not allowed for it", body = ErrorBody),
+        (status = 404, description = "there is no such media source", body = ErrorBody),
+        (status = 409, description = "playback was stopped or another source was started before a link played", body = ErrorBody),
+        (status = 502, description = "none of the links could be played", body = ErrorBody),
+        (status = 503, description = "the player can't be reached", body = ErrorBody),
+    )
+)]
+pub async fn start_playback(
+    player: web::Data<PlayerHandle>,
+    watchdog: web::Data<WatchdogHandle>,
+    body: Json<PlaybackTarget>,
+) -> Result<Json<Playback>, HomeRadioError> {
+    player.start(body.0).await?;
+    playback(&player, &watchdog).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/playback/stop",
+    tag = "playback",
+    responses(
+        (status = 200, body = Playback),
+        (status = 503, description = "the player can't be reached", body = ErrorBody),
+    )
+)]
+pub async fn stop_playback(
+    player: web::Data<PlayerHandle>,
+    watchdog: web::Data<WatchdogHandle>,
+) -> Result<Json<Playback>, HomeRadioError> {
+    player.stop().await?;
+    playback(&player, &watchdog).await
+}
+
+async fn playback(
+    player: &PlayerHandle,
+    watchdog: &WatchdogHandle,
+) -> Result<Json<Playback>, HomeRadioError> {
+    Ok(Json(Playback {
+        current: player.current_media_source().await?,
+        active: watchdog.active(),
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/volume",
+    tag = "playback",
+    responses((status = 200, body = Volume))
+)]
+pub async fn get_volume(player: web::Data<PlayerHandle>) -> Result<Json<Volume>, HomeRadioError> {
+    Ok(Json(Volume {
+        volume: player.volume().await?,
+    }))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/volume",
+    tag = "playback",
+    request_body = Volume,
+    responses(
+        (status = 200, body = Volume),
+        (status = 400, description = "the body is not a volume", body = ErrorBody),
+        (status = 503, description = "the player can't be reached", body = ErrorBody),
+    )
+)]
+pub async fn set_volume(
+    player: web::Data<PlayerHandle>,
+    body: Json<Volume>,
+) -> Result<Json<Volume>, HomeRadioError> {
+    player.set_volume(body.volume).await?;
+    Ok(body)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/status",
+    tag = "diagnostics",
+    responses((status = 200, description = "what the player and the background tasks report", body = StatusResponse))
+)]
+pub async fn get_status(
+    vlc: web::Data<SupervisorHandle>,
+    watchdog: web::Data<WatchdogHandle>,
+    reconciler: web::Data<ReconcileHandle>,
+    status: web::Data<StatusHandle>,
+    storage: web::Data<StorageHandle>,
+) -> Json<StatusResponse> {
+    let vlc = vlc.report();
+    let watchdog = watchdog.report();
+    let storage = storage.report().await;
+    let last_error = [
+        vlc.last_error(),
+        watchdog.last_error(),
+        storage.last_error.clone(),
+    ]
+    .into_iter()
+    .flatten()
+    .max_by_key(|error| error.timestamp);
+    Json(StatusResponse {
+        player: status.current(),
+        vlc,
+        watchdog,
+        reconciler: reconciler.report(),
+        storage,
+        last_error,
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "diagnostics",
+    responses((status = 200, description = "the process is up", content_type = "text/plain", body = String))
+)]
+pub async fn healthz() -> impl Responder {
+    HttpResponse::Ok().content_type("text/plain").body("ok")
+}
+
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "diagnostics",
+    responses(
+        (status = 200, description = "vlc is reachable and the state dir is writable", body = Readiness),
+        (status = 503, description = "one of the checks failed", body = Readiness),
+    )
+)]
+pub async fn readyz(
+    status: web::Data<StatusHandle>,
+    storage: web::Data<StorageHandle>,
+) -> HttpResponse {
+    let vlc_reachable = status.poll(READY_TIMEOUT).await.is_some();
+    let state_dir_writable = storage.writable().await;
+    let ready = vlc_reachable && state_dir_writable;
+    let readiness = Readiness {
+        ready,
+        vlc_reachable,
+        state_dir_writable,
+    };
+    if ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/events",
+    tag = "diagnostics",
+    responses((
+        status = 200,
+        description = "server-sent events, the `data` of every event is an `Event`",
+        content_type = "text/event-stream",
+        body = String,
+    ))
+)]
+pub async fn get_events(events: web::Data<EventBus>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("cache-control", "no-cache"))
+        .streaming(events.sse_stream())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/openapi.json",
+    tag = "diagnostics",
+    responses((status = 200, description = "this document"))
+)]
+pub async fn get_openapi() -> impl Responder {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "diagnostics",
+    responses((status = 200, description = "metrics in the Prometheus text format", content_type = "text/plain", body = String))
+)]
+pub async fn get_metrics(
+    metrics: web::Data<Metrics>,
+    status: web::Data<StatusHandle>,
+    watchdog: web::Data<WatchdogHandle>,
+    vlc: web::Data<SupervisorHandle>,
+    storage: web::Data<StorageHandle>,
+) -> impl Responder {
+    let body = metrics.render(
+        status.current().as_ref(),
+        &watchdog.report(),
+        &vlc.report(),
+        storage.errors(),
+    );
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct LogFilter {
+    /// e.g. `info,home_radio::media_service=debug`
+    filter: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/logging",
+    tag = "diagnostics",
+    responses((status = 200, description = "the log filter in effect", body = LogFilter))
+)]
+pub async fn get_log_filter(logs: web::Data<LogControl>) -> Json<LogFilter> {
+    Json(LogFilter {
+        filter: logs.filter(),
+    })
+}
+
+/// Replaces the log filter until the next restart, which goes back to `logging.level`.
+#[utoipa::path(
+    put,
+    path = "/api/v1/logging",
+    tag = "diagnostics",
+    request_body = LogFilter,
+    responses(
+        (status = 200, body = LogFilter),
+        (status = 400, description = "the filter can't be parsed", body = ErrorBody),
+    )
+)]
+pub async fn set_log_filter(
+    logs: web::Data<LogControl>,
+    body: Json<LogFilter>,
+) -> Result<Json<LogFilter>, HomeRadioError> {
+    logs.set_filter(&body.filter)?;
+    info!("log filter changed to {}", body.filter);
+    Ok(get_log_filter(logs).await)
+}
+
+#[utoipa::path(
+    get,
+    path = "/logs",
+    tag = "diagnostics",
+    params(RecordQuery),
+    responses((status = 200, description = "the latest log records kept in memory, oldest first", body = [LogRecord]))
+)]
+pub async fn get_logs(
+    logs: web::Data<LogControl>,
+    query: web::Query<RecordQuery>,
+) -> Json<Vec<LogRecord>> {
+    Json(logs.records(&query))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct LoginRequest {
+    user: String,
+    password: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct LoginResponse {
+    user: String,
+    scope: auth::Scope,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/login",
+    tag = "auth",
+    request_body = LoginRequest,
+    responses(
+        (status = 200, description = "the session cookie is set", body = LoginResponse),
+        (status = 400, description = "authentication is not enabled", body = ErrorBody),
+        (status = 401, description = "unknown user or wrong password", body = ErrorBody),
+    )
+)]
+pub async fn login(
+    auth: web::Data<Auth>,
+    body: Json<LoginRequest>,
+    req: HttpRequest,
+) -> Result<HttpResponse, HomeRadioError> {
+    let LoginRequest { user, password } = body.0;
+    let secure = req.app_config().secure();
+    let (cookie, scope) = auth.login(user.clone(), password, secure).await?;
+    Ok(HttpResponse::Ok()
+        .cookie(cookie)
+        .json(LoginResponse { user, scope }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/logout",
+    tag = "auth",
+    responses((status = 204, description = "the session is ended and its cookie removed"))
+)]
+pub async fn logout(auth: web::Data<Auth>, req: HttpRequest) -> HttpResponse {
+    HttpResponse::NoContent()
+        .del_cookie(&auth.logout(&req))
+        .finish()
+}
@@ -0,0 +1,380 @@
+mod store;
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    dev::ServiceRequest,
+    http::{header, Method},
+    HttpRequest,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+use crate::errors::HomeRadioError;
+
+pub use store::*;
+
+const SESSION_COOKIE: &str = "home_radio_session";
+
+/// how often the store file is checked for changes made by the cli
+const STORE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Paths anyone may request, the ui has to load before its user can log in.
+const PUBLIC_PATHS: &[&str] = &[
+    "/",
+    "/index.html",
+    "/index.css",
+    "/index.js",
+    "/common.js",
+    "/form.js",
+    "/login.html",
+    "/login.js",
+    "/diagnostics.html",
+    "/diagnostics.js",
+    "/add-media-form.html",
+    "/favicon.ico",
+    "/android-chrome-192x192.png",
+    "/api/v1/login",
+    "/api/v1/logout",
+    "/api/v1/openapi.json",
+    "/healthz",
+    "/readyz",
+];
+
+/// What a user or token may do, every scope includes the ones before it.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// look at sources, playback and diagnostics
+    Read,
+    /// start and stop playback and change the volume
+    Control,
+    /// change the stored media sources
+    Admin,
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "control" => Ok(Scope::Control),
+            "admin" => Ok(Scope::Admin),
+            _ => Err("expected read, control or admin".into()),
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Scope::Read => "read",
+            Scope::Control => "control",
+            Scope::Admin => "admin",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The scope a request needs, `None` for public paths. Expects the decoded path the router matches.
+pub fn required_scope(method: &Method, path: &str) -> Option<Scope> {
+    let path = match path.trim_end_matches('/') {
+        "" => "/",
+        trimmed => trimmed,
+    };
+    if PUBLIC_PATHS.contains(&path) {
+        return None;
+    }
+    Some(match (method, path) {
+        (_, "/api/v1/logging") | (_, "/logs") => Scope::Admin,
+        (&Method::GET, _) | (&Method::HEAD, _) => Scope::Read,
+        (&Method::POST, "/api/v1/sources") | (&Method::PUT, "/media") => Scope::Admin,
+        _ => Scope::Control,
+    })
+}
+
+struct Session {
+    user: String,
+    scope: Scope,
+    expires: Instant,
+}
+
+/// The store as it was when the file was last read, the cli changes the file while the server runs.
+struct CachedStore {
+    modified: Option<SystemTime>,
+    store: Arc<AuthStore>,
+}
+
+struct Inner {
+    path: PathBuf,
+    store: Mutex<CachedStore>,
+    sessions: Mutex<HashMap<String, Session>>,
+    session_ttl: Duration,
+}
+
+/// Decides who may send a request. Cheap to clone, a disabled one lets everything through.
+#[derive(Clone, Default)]
+pub struct Auth {
+    inner: Option<Arc<Inner>>,
+}
+
+impl Auth {
+    pub fn new(dir: &Path, session_ttl: Duration) -> Result<Self, HomeRadioError> {
+        let path = AuthStore::path(dir);
+        let store = AuthStore::load(&path)?;
+        if store.users.is_empty() && store.tokens.is_empty() {
+            warn!("authentication is enabled, but there are no users or api tokens yet, add them with `home-radio user add` or `home-radio token create`");
+        }
+        let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+        Ok(Auth {
+            inner: Some(Arc::new(Inner {
+                path,
+                store: Mutex::new(CachedStore {
+                    modified,
+                    store: Arc::new(store),
+                }),
+                sessions: Mutex::new(HashMap::new()),
+                session_ttl,
+            })),
+        })
+    }
+
+    /// Rejects requests that don't carry a session or token with the scope the route needs.
+    pub fn check(&self, req: &ServiceRequest) -> Result<(), HomeRadioError> {
+        let inner = match &self.inner {
+            Some(inner) => inner,
+            None => return Ok(()),
+        };
+        // the router matches the percent-decoded path, `req.path()` is still encoded
+        let required = match required_scope(req.method(), req.match_info().path()) {
+            Some(required) => required,
+            None => return Ok(()),
+        };
+        let bearer = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let scope = match bearer {
+            Some(token) => inner.store().token_scope(token.trim()),
+            None => req
+                .cookie(SESSION_COOKIE)
+                .and_then(|cookie| inner.session_scope(cookie.value())),
+        };
+        match scope {
+            Some(scope) if scope >= required => Ok(()),
+            Some(_) => Err(HomeRadioError::Forbidden(required)),
+            None => Err(HomeRadioError::Unauthenticated),
+        }
+    }
+
+    /// Reads the store again whenever its file changes, requests don't touch the file.
+    pub async fn watch_store(self) {
+        let inner = match self.inner {
+            Some(inner) => inner,
+            None => return,
+        };
+        loop {
+            sleep(STORE_CHECK_INTERVAL).await;
+            inner.refresh().await;
+        }
+    }
+
+    /// Checks the password and starts a session, returns the cookie for it.
+    /// The cookie is only sent back over https if the request came in that way.
+    pub async fn login(
+        &self,
+        user: String,
+        password: String,
+        secure: bool,
+    ) -> Result<(Cookie<'static>, Scope), HomeRadioError> {
+        let inner = self
+            .inner
+            .as_ref()
+            .ok_or_else(|| HomeRadioError::InvalidInput("authentication is not enabled".into()))?;
+        let store = inner.store();
+        let name = user.clone();
+        // argon2 takes a while by design
+        let scope = tokio::task::spawn_blocking(move || store.verify_password(&name, &password))
+            .await?
+            .ok_or(HomeRadioError::InvalidCredentials)?;
+
+        let id = random_string(32);
+        let now = Instant::now();
+        let mut sessions = inner.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.expires > now);
+        info!("{} logged in", &user);
+        sessions.insert(
+            id.clone(),
+            Session {
+                user,
+                scope,
+                expires: now + inner.session_ttl,
+            },
+        );
+        Ok((session_cookie(id, secure), scope))
+    }
+
+    /// Ends the session of the request, returns the cookie to remove.
+    pub fn logout(&self, req: &HttpRequest) -> Cookie<'static> {
+        if let (Some(inner), Some(cookie)) = (&self.inner, req.cookie(SESSION_COOKIE)) {
+            if let Some(session) = inner.sessions.lock().unwrap().remove(cookie.value()) {
+                info!("{} logged out", session.user);
+            }
+        }
+        session_cookie(String::new(), req.app_config().secure())
+    }
+}
+
+impl Inner {
+    fn store(&self) -> Arc<AuthStore> {
+        self.store.lock().unwrap().store.clone()
+    }
+
+    /// Reloads the store if its file changed since it was read.
+    async fn refresh(&self) {
+        let modified = tokio::fs::metadata(&self.path)
+            .await
+            .and_then(|m| m.modified())
+            .ok();
+        if modified == self.store.lock().unwrap().modified {
+            return;
+        }
+        let path = self.path.clone();
+        let loaded = tokio::task::spawn_blocking(move || AuthStore::load(&path))
+            .await
+            .unwrap_or_else(|e| Err(e.into()));
+        let mut cached = self.store.lock().unwrap();
+        // a file that can't be read is only reported once
+        cached.modified = modified;
+        match loaded {
+            Ok(store) => {
+                info!("reloaded users and api tokens from {}", self.path.display());
+                cached.store = Arc::new(store);
+            }
+            Err(e) => error!(
+                "could not reload {}, keeping the previous users and api tokens: {}",
+                self.path.display(),
+                e
+            ),
+        }
+    }
+
+    fn session_scope(&self, id: &str) -> Option<Scope> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(id)?;
+        if session.expires <= Instant::now() {
+            sessions.remove(id);
+            return None;
+        }
+        Some(session.scope)
+    }
+}
+
+/// Session cookies are kept away from scripts and other sites, and on tls listeners from plain http.
+fn session_cookie(id: String, secure: bool) -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE, id)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(secure)
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn scope_of(method: Method, uri: &str) -> Option<Scope> {
+        let req = TestRequest::default()
+            .method(method.clone())
+            .uri(uri)
+            .to_srv_request();
+        required_scope(&method, req.match_info().path())
+    }
+
+    #[test]
+    fn public_paths_need_no_scope() {
+        assert_eq!(scope_of(Method::GET, "/"), None);
+        assert_eq!(scope_of(Method::GET, "/index.html"), None);
+        assert_eq!(scope_of(Method::POST, "/api/v1/login"), None);
+        assert_eq!(scope_of(Method::GET, "/readyz"), None);
+    }
+
+    #[test]
+    fn scope_follows_method_and_path() {
+        assert_eq!(scope_of(Method::GET, "/api/v1/sources"), Some(Scope::Read));
+        assert_eq!(scope_of(Method::HEAD, "/volume"), Some(Scope::Read));
+        assert_eq!(scope_of(Method::PUT, "/volume"), Some(Scope::Control));
+        assert_eq!(
+            scope_of(Method::POST, "/api/v1/playback"),
+            Some(Scope::Control)
+        );
+        assert_eq!(
+            scope_of(Method::POST, "/api/v1/sources"),
+            Some(Scope::Admin)
+        );
+        assert_eq!(scope_of(Method::PUT, "/media"), Some(Scope::Admin));
+        assert_eq!(scope_of(Method::GET, "/logs"), Some(Scope::Admin));
+        assert_eq!(scope_of(Method::PUT, "/api/v1/logging"), Some(Scope::Admin));
+    }
+
+    #[test]
+    fn encoded_paths_need_the_scope_of_the_decoded_route() {
+        assert_eq!(scope_of(Method::GET, "/%6Cogs"), Some(Scope::Admin));
+        assert_eq!(
+            scope_of(Method::PUT, "/api/v1/%6Cogging"),
+            Some(Scope::Admin)
+        );
+        assert_eq!(
+            scope_of(Method::POST, "/api/v1/%73ources"),
+            Some(Scope::Admin)
+        );
+        assert_eq!(scope_of(Method::PUT, "/%6Dedia"), Some(Scope::Admin));
+    }
+
+    #[test]
+    fn trailing_slashes_are_ignored() {
+        assert_eq!(scope_of(Method::GET, "/logs/"), Some(Scope::Admin));
+        assert_eq!(
+            scope_of(Method::POST, "/api/v1/sources//"),
+            Some(Scope::Admin)
+        );
+        assert_eq!(scope_of(Method::GET, "/index.html/"), None);
+    }
+
+    #[tokio::test]
+    async fn store_is_reloaded_after_the_file_changed() {
+        let dir = TempDir::new();
+        std::fs::create_dir_all(dir.path()).unwrap();
+        let auth = Auth::new(dir.path(), Duration::from_secs(60)).unwrap();
+        let inner = auth.inner.as_ref().unwrap();
+
+        let mut store = AuthStore::default();
+        let token = store.create_token("kitchen", Scope::Control);
+        store.save(&AuthStore::path(dir.path())).await.unwrap();
+        assert_eq!(inner.store().token_scope(&token), None);
+
+        inner.refresh().await;
+        assert_eq!(inner.store().token_scope(&token), Some(Scope::Control));
+    }
+
+    #[test]
+    fn session_cookie_is_secure_on_tls_listeners() {
+        assert_eq!(session_cookie("id".into(), true).secure(), Some(true));
+        assert_ne!(session_cookie("id".into(), false).secure(), Some(true));
+    }
+}
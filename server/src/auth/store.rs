@@ -0,0 +1,177 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{backend::write_atomic, errors::HomeRadioError, media_service::unix_time};
+
+use super::Scope;
+
+/// name of the file in the state dir that holds the users and api tokens
+pub const AUTH_FILE: &str = "auth.json";
+/// Format version of `auth.json` written by this release.
+const AUTH_VERSION: u64 = 1;
+/// makes tokens recognizable, e.g. for secret scanners
+const TOKEN_PREFIX: &str = "hr_";
+
+/// Users of the ui and api tokens. Only hashes of passwords and tokens are kept.
+#[derive(Deserialize, Serialize)]
+pub struct AuthStore {
+    version: u64,
+    #[serde(default)]
+    pub users: Vec<User>,
+    #[serde(default)]
+    pub tokens: Vec<ApiToken>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct User {
+    pub name: String,
+    pub scope: Scope,
+    /// argon2 hash in PHC string format
+    password_hash: String,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ApiToken {
+    /// identifies the token when it is listed or revoked
+    pub id: String,
+    pub name: String,
+    pub scope: Scope,
+    /// seconds since the unix epoch
+    pub created_at: u64,
+    /// sha256 of the token, the token itself is only shown once when it is created
+    hash: String,
+}
+
+impl Default for AuthStore {
+    fn default() -> Self {
+        AuthStore {
+            version: AUTH_VERSION,
+            users: Vec::new(),
+            tokens: Vec::new(),
+        }
+    }
+}
+
+impl AuthStore {
+    pub fn path(dir: &Path) -> PathBuf {
+        dir.join(AUTH_FILE)
+    }
+
+    /// Reads the store, a missing file is an empty store.
+    pub fn load(path: &Path) -> Result<Self, HomeRadioError> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(AuthStore::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let store: AuthStore = serde_json::from_str(&content)?;
+        if store.version > AUTH_VERSION {
+            return Err(HomeRadioError::UnsupportedStateVersion {
+                path: path.to_string_lossy().into(),
+                version: store.version,
+            });
+        }
+        Ok(store)
+    }
+
+    pub async fn save(&self, path: &Path) -> Result<(), HomeRadioError> {
+        let raw = serde_json::to_vec_pretty(self)?;
+        // holds password hashes and token digests
+        write_atomic(path, &raw, 0o600).await
+    }
+
+    /// Adds the user or replaces the password and scope of an existing one.
+    pub fn set_user(
+        &mut self,
+        name: &str,
+        password: &str,
+        scope: Scope,
+    ) -> Result<(), HomeRadioError> {
+        if name.is_empty() || password.is_empty() {
+            return Err(HomeRadioError::InvalidInput(
+                "user name and password must not be empty".into(),
+            ));
+        }
+        let salt = SaltString::generate(&mut OsRng);
+        let password_hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| HomeRadioError::InvalidInput(e.to_string()))?
+            .to_string();
+        let user = User {
+            name: name.into(),
+            scope,
+            password_hash,
+        };
+        match self.users.iter_mut().find(|user| user.name == name) {
+            Some(existing) => *existing = user,
+            None => self.users.push(user),
+        }
+        Ok(())
+    }
+
+    pub fn remove_user(&mut self, name: &str) -> bool {
+        let before = self.users.len();
+        self.users.retain(|user| user.name != name);
+        self.users.len() != before
+    }
+
+    /// Returns the scope of the user if the password matches. Slow on purpose, don't call it on the event loop.
+    pub fn verify_password(&self, name: &str, password: &str) -> Option<Scope> {
+        let user = self.users.iter().find(|user| user.name == name)?;
+        let hash = PasswordHash::new(&user.password_hash).ok()?;
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .ok()
+            .map(|_| user.scope)
+    }
+
+    /// Creates a token and returns it, it can't be recovered later.
+    pub fn create_token(&mut self, name: &str, scope: Scope) -> String {
+        let token = format!("{}{}", TOKEN_PREFIX, random_string(40));
+        self.tokens.push(ApiToken {
+            id: random_string(8).to_lowercase(),
+            name: name.into(),
+            scope,
+            created_at: unix_time(),
+            hash: hash_token(&token),
+        });
+        token
+    }
+
+    pub fn revoke_token(&mut self, id: &str) -> bool {
+        let before = self.tokens.len();
+        self.tokens.retain(|token| token.id != id);
+        self.tokens.len() != before
+    }
+
+    pub fn token_scope(&self, token: &str) -> Option<Scope> {
+        let hash = hash_token(token);
+        self.tokens
+            .iter()
+            .find(|stored| stored.hash == hash)
+            .map(|stored| stored.scope)
+    }
+}
+
+/// Tokens are long random strings, so a fast hash is enough to keep them from being usable when the file leaks.
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+pub(super) fn random_string(len: usize) -> String {
+    OsRng
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
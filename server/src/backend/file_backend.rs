@@ -1,13 +1,48 @@
-use std::path::{Path, PathBuf};
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
 
-use log::info;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use tokio::fs::{self, File, OpenOptions};
+use tracing::{error, info, warn};
 
 use crate::errors::HomeRadioError;
 
-use serde_json;
 use tokio::io::AsyncWriteExt;
 
+use super::{next_id, MediaSource, Storage};
+
+/// names of the state files inside the state dir
+pub const MEDIA_SOURCES_FILE: &str = "media-sources.json";
+pub const VOLUME_FILE: &str = "volume";
+pub const CURRENT_FILE: &str = "currently-playing";
+
+/// Permissions of the state files, before the umask is applied.
+const STATE_FILE_MODE: u32 = 0o644;
+
+/// Format version of `media-sources.json` written by this release.
+pub const MEDIA_SOURCES_VERSION: u64 = 2;
+
+/// Upgrades of `media-sources.json`, entry `i` turns version `i` into version `i + 1`.
+/// Never change an existing entry, append a new one and bump `MEDIA_SOURCES_VERSION` instead.
+const MIGRATIONS: &[Migration] = &[wrap_in_envelope, assign_ids];
+
+/// The version `assign_ids` upgrades to, `currently-playing` holds the link of the source
+/// instead of its id next to older versions.
+const IDS_VERSION: u64 = 2;
+
+type Migration = fn(Value) -> Result<Value, HomeRadioError>;
+
+/// Content of `media-sources.json`.
+#[derive(Deserialize, Serialize)]
+struct MediaSourcesFile {
+    version: u64,
+    media_sources: Vec<MediaSource>,
+}
+
 pub struct FileBackend {
     media_file_path: PathBuf,
     volume_path: PathBuf,
@@ -23,9 +58,9 @@ impl FileBackend {
         let mut volume_file_path = media_sources_file.clone();
         let mut currently_playing_path = media_sources_file.clone();
 
-        media_sources_file.push("media-sources.json");
-        volume_file_path.push("volume");
-        currently_playing_path.push("currently-playing");
+        media_sources_file.push(MEDIA_SOURCES_FILE);
+        volume_file_path.push(VOLUME_FILE);
+        currently_playing_path.push(CURRENT_FILE);
 
         for i in [
             &media_sources_file,
@@ -49,51 +84,65 @@ impl FileBackend {
                 }
             }
         }
+
+        // a file from a newer release is not corrupt, `upgrade_file` refuses to touch it
+        recover(&media_sources_file, |content| {
+            !matches!(
+                decode(&media_sources_file, content),
+                Err(HomeRadioError::Serde(_))
+            )
+        })
+        .await?;
+        recover(&volume_file_path, |content| content.parse::<u16>().is_ok()).await?;
+        recover(&currently_playing_path, |_| true).await?;
+        upgrade_file(&media_sources_file, &currently_playing_path).await?;
+
         Ok(FileBackend {
             media_file_path: media_sources_file,
             volume_path: volume_file_path,
             currently_playing_path,
         })
     }
+}
 
-    pub async fn get_media_sources(
-        &self,
-    ) -> Result<Vec<super::MediaSource>, crate::errors::HomeRadioError> {
+#[async_trait]
+impl Storage for FileBackend {
+    async fn get_media_sources(&self) -> Result<Vec<MediaSource>, HomeRadioError> {
         let content = tokio::fs::read_to_string(&self.media_file_path).await?;
-        if content.is_empty() {
-            return Ok(Vec::new());
-        }
-        let result = serde_json::from_str(&content)?;
-        return Ok(result);
+        let (sources, _) = decode(&self.media_file_path, &content)?;
+        Ok(sources)
     }
 
-    pub async fn add_media_source(
-        &self,
-        source: super::MediaSource,
-    ) -> Result<(), crate::errors::HomeRadioError> {
+    async fn add_media_source(&self, mut source: MediaSource) -> Result<(), HomeRadioError> {
         let mut sources = self.get_media_sources().await?;
 
-        let prev_source = sources.iter_mut().find(|m| &m.name == &source.name);
+        if source.id == 0 {
+            source.id = match sources.iter().find(|m| m.name == source.name) {
+                Some(prev_source) => prev_source.id,
+                None => next_id(&sources),
+            };
+        }
+        let prev_source = sources.iter_mut().find(|m| m.id == source.id);
         if let Some(prev_source) = prev_source {
             *prev_source = source;
         } else {
             sources.push(source);
         }
 
-        let mut f = File::create(&self.media_file_path).await?;
-
-        let raw = serde_json::to_vec_pretty(&sources)?;
-        f.write(&raw).await?;
-
-        Ok(())
+        let raw = encode(sources)?;
+        write_atomic(&self.media_file_path, &raw, STATE_FILE_MODE).await
     }
 
-    pub async fn set_volume(&self, volume: u16) -> Result<(), crate::errors::HomeRadioError> {
-        tokio::fs::write(&self.volume_path, volume.to_string()).await?;
-        Ok(())
+    async fn set_volume(&self, volume: u16) -> Result<(), HomeRadioError> {
+        write_atomic(
+            &self.volume_path,
+            volume.to_string().as_bytes(),
+            STATE_FILE_MODE,
+        )
+        .await
     }
 
-    pub async fn get_volume(&self) -> Result<u16, crate::errors::HomeRadioError> {
+    async fn get_volume(&self) -> Result<u16, HomeRadioError> {
         let raw_vol = tokio::fs::read_to_string(&self.volume_path).await?;
         if raw_vol.is_empty() {
             return Ok(100);
@@ -101,7 +150,7 @@ impl FileBackend {
         Ok(raw_vol.parse()?)
     }
 
-    pub async fn remove_current_media_source(&self) -> Result<(), HomeRadioError> {
+    async fn remove_current_media_source(&self) -> Result<(), HomeRadioError> {
         let result = fs::remove_file(&self.currently_playing_path).await;
         if let Err(e) = result {
             match e.kind() {
@@ -112,23 +161,424 @@ impl FileBackend {
         Ok(())
     }
 
-    pub async fn get_current_media_source(&self) -> Result<Option<String>, HomeRadioError> {
+    async fn get_current_media_source(&self) -> Result<Option<u64>, HomeRadioError> {
         let result = fs::read_to_string(&self.currently_playing_path).await;
-        if let Ok(url) = result {
-            if url.is_empty() {
+        if let Ok(id) = result {
+            if id.is_empty() {
                 return Ok(None);
             }
-            return Ok(Some(url));
+            return Ok(Some(id.trim().parse()?));
         }
         let err = result.err().unwrap();
         match err.kind() {
-            std::io::ErrorKind::NotFound => return Ok(None),
-            _ => return Err(HomeRadioError::Io(err)),
+            std::io::ErrorKind::NotFound => Ok(None),
+            _ => Err(HomeRadioError::Io(err)),
         }
     }
 
-    pub async fn set_current_media_source(&self, url: &str) -> Result<(), HomeRadioError> {
-        fs::write(&self.currently_playing_path, &url).await?;
-        Ok(())
+    async fn set_current_media_source(&self, id: u64) -> Result<(), HomeRadioError> {
+        write_atomic(
+            &self.currently_playing_path,
+            id.to_string().as_bytes(),
+            STATE_FILE_MODE,
+        )
+        .await
+    }
+}
+
+/// Version 0 is the bare array of media sources that was written before the file had a version.
+fn wrap_in_envelope(sources: Value) -> Result<Value, HomeRadioError> {
+    Ok(json!({ "version": 1, "media_sources": sources }))
+}
+
+/// Version 1 identified sources by name and link only, they are numbered in the order they were added.
+fn assign_ids(mut file: Value) -> Result<Value, HomeRadioError> {
+    if let Some(sources) = file["media_sources"].as_array_mut() {
+        for (i, source) in sources.iter_mut().enumerate() {
+            source["id"] = json!(i + 1);
+        }
+    }
+    file["version"] = json!(2);
+    Ok(file)
+}
+
+/// Parses the content of `media-sources.json` in any known format,
+/// returns the sources and the version the content was in.
+fn decode(path: &Path, content: &str) -> Result<(Vec<MediaSource>, u64), HomeRadioError> {
+    if content.is_empty() {
+        return Ok((Vec::new(), MEDIA_SOURCES_VERSION));
+    }
+    let mut value: Value = serde_json::from_str(content)?;
+    let version = if value.is_array() {
+        0
+    } else {
+        // a missing version fails below when the content is parsed as the current format
+        value["version"].as_u64().unwrap_or(MEDIA_SOURCES_VERSION)
+    };
+    if version > MEDIA_SOURCES_VERSION {
+        return Err(HomeRadioError::UnsupportedStateVersion {
+            path: path.to_string_lossy().into(),
+            version,
+        });
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        value = migration(value)?;
+    }
+    let file: MediaSourcesFile = serde_json::from_value(value)?;
+    Ok((file.media_sources, version))
+}
+
+fn encode(media_sources: Vec<MediaSource>) -> Result<Vec<u8>, HomeRadioError> {
+    Ok(serde_json::to_vec_pretty(&MediaSourcesFile {
+        version: MEDIA_SOURCES_VERSION,
+        media_sources,
+    })?)
+}
+
+/// Rewrites a `media-sources.json` of an older version in the current format,
+/// together with the `currently-playing` file next to it. The old file is kept as `<path>.v<version>.bak`.
+async fn upgrade_file(path: &Path, current_path: &Path) -> Result<(), HomeRadioError> {
+    let content = fs::read_to_string(path).await?;
+    let (sources, version) = decode(path, &content)?;
+    if version == MEDIA_SOURCES_VERSION {
+        return Ok(());
+    }
+    let backup = with_suffix(path, &format!("v{}.bak", version));
+    info!(
+        "upgrading {} from version {} to {}, keeping the old file as {}",
+        &path.to_string_lossy(),
+        version,
+        MEDIA_SOURCES_VERSION,
+        &backup.to_string_lossy()
+    );
+    fs::copy(path, &backup).await?;
+    File::open(&backup).await?.sync_all().await?;
+    // before the new media sources, an interrupted upgrade must not leave a link next to a file with ids
+    if version < IDS_VERSION {
+        upgrade_current(current_path, &sources).await?;
+    }
+    let raw = encode(sources)?;
+    write_atomic(path, &raw, STATE_FILE_MODE).await
+}
+
+/// The link of the current source in `currently-playing` is replaced with the id of the source,
+/// or dropped if no stored source has that link.
+async fn upgrade_current(path: &Path, sources: &[MediaSource]) -> Result<(), HomeRadioError> {
+    let content = fs::read_to_string(path).await?;
+    let link = content.trim();
+    if link.is_empty() {
+        return Ok(());
+    }
+    match sources.iter().find(|src| src.link == link) {
+        Some(src) => {
+            info!("recording the current source {} by its id {}", link, src.id);
+            write_atomic(path, src.id.to_string().as_bytes(), STATE_FILE_MODE).await
+        }
+        None => {
+            warn!(
+                "{} is not a stored media source, forgetting it as the current one",
+                link
+            );
+            write_atomic(path, &[], STATE_FILE_MODE).await
+        }
+    }
+}
+
+/// `path` with `suffix` appended to the file name, e.g. `media-sources.json.bak`
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Replaces the content of `path` so that a crash at any point leaves either the old or the new content behind.
+/// The previous content is kept in `<path>.bak`, the new file is created with `mode` from the start.
+pub(crate) async fn write_atomic(
+    path: &Path,
+    content: &[u8],
+    mode: u32,
+) -> Result<(), HomeRadioError> {
+    let tmp = with_suffix(path, "tmp");
+    // the mode only applies to a new file, not to a leftover of an interrupted write
+    match fs::remove_file(&tmp).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(HomeRadioError::Io(e)),
+        _ => {}
+    }
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&tmp)
+        .await?;
+    f.write_all(content).await?;
+    f.sync_all().await?;
+    drop(f);
+
+    let has_content = fs::metadata(path)
+        .await
+        .map(|meta| meta.len() > 0)
+        .unwrap_or(false);
+    if has_content {
+        let bak = with_suffix(path, "bak");
+        fs::copy(path, &bak).await?;
+        File::open(&bak).await?.sync_all().await?;
+    }
+
+    fs::rename(&tmp, path).await?;
+    // the rename itself is only durable once the directory is synced
+    if let Some(dir) = path.parent() {
+        File::open(dir).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+/// Replaces a corrupt state file with its backup, or with an empty file if there is no usable backup.
+/// The corrupt file is kept as `<path>.corrupt` for inspection.
+async fn recover<F: Fn(&str) -> bool>(path: &Path, is_valid: F) -> Result<(), HomeRadioError> {
+    // leftover of a write that was interrupted before the rename
+    let _ = fs::remove_file(with_suffix(path, "tmp")).await;
+
+    let content = match fs::read_to_string(path).await {
+        Ok(content) => Some(content),
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => None,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(HomeRadioError::Io(e)),
+    };
+    if let Some(content) = content {
+        if content.is_empty() || is_valid(&content) {
+            return Ok(());
+        }
+    }
+
+    let corrupt = with_suffix(path, "corrupt");
+    error!(
+        "{} is corrupt, moving it to {}",
+        &path.to_string_lossy(),
+        &corrupt.to_string_lossy()
+    );
+    fs::rename(path, &corrupt).await?;
+
+    let backup = fs::read_to_string(with_suffix(path, "bak")).await;
+    match backup {
+        Ok(backup) if is_valid(&backup) => {
+            warn!("restoring {} from its backup", &path.to_string_lossy());
+            write_atomic(path, backup.as_bytes(), STATE_FILE_MODE).await
+        }
+        _ => {
+            warn!(
+                "no usable backup of {}, starting with an empty one",
+                &path.to_string_lossy()
+            );
+            write_atomic(path, &[], STATE_FILE_MODE).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{source, TempDir};
+
+    const BARE_ARRAY: &str = r#"[
+        {"link": "http://radio.example/stream", "name": "Radio", "media_type": "Radio", "currently_playing": null, "default_source": true}
+    ]"#;
+
+    /// A state dir with one media source and the volume 40, the backup of the volume holds 30.
+    async fn dir_with_backup() -> TempDir {
+        let dir = TempDir::new();
+        let backend = FileBackend::new(dir.path()).await.unwrap();
+        backend
+            .add_media_source(source("Radio", "http://radio.example/stream"))
+            .await
+            .unwrap();
+        backend.set_volume(30).await.unwrap();
+        backend.set_volume(40).await.unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn truncated_file_is_restored_from_backup() {
+        let dir = dir_with_backup().await;
+        let path = dir.join("media-sources.json");
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::copy(&path, with_suffix(&path, "bak")).unwrap();
+        let truncated = &content[..content.len() / 2];
+        std::fs::write(&path, truncated).unwrap();
+
+        let backend = FileBackend::new(dir.path()).await.unwrap();
+        let sources = backend.get_media_sources().await.unwrap();
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources[0].name, "Radio");
+        let corrupt = std::fs::read_to_string(with_suffix(&path, "corrupt")).unwrap();
+        assert_eq!(corrupt, truncated);
+    }
+
+    #[tokio::test]
+    async fn corrupt_volume_is_restored_from_backup() {
+        let dir = dir_with_backup().await;
+        let path = dir.join("volume");
+        std::fs::write(&path, "\u{0}\u{0}garbage").unwrap();
+
+        let backend = FileBackend::new(dir.path()).await.unwrap();
+        assert_eq!(backend.get_volume().await.unwrap(), 30);
+        let corrupt = std::fs::read(with_suffix(&path, "corrupt")).unwrap();
+        assert_eq!(corrupt, b"\0\0garbage");
+    }
+
+    #[tokio::test]
+    async fn corrupt_file_without_backup_starts_empty() {
+        let dir = TempDir::new();
+        std::fs::create_dir_all(dir.path()).unwrap();
+        let path = dir.join("media-sources.json");
+        std::fs::write(&path, "[{\"name\": ").unwrap();
+
+        let backend = FileBackend::new(dir.path()).await.unwrap();
+        assert!(backend.get_media_sources().await.unwrap().is_empty());
+        let corrupt = std::fs::read_to_string(with_suffix(&path, "corrupt")).unwrap();
+        assert_eq!(corrupt, "[{\"name\": ");
+    }
+
+    #[tokio::test]
+    async fn corrupt_backup_is_not_restored() {
+        let dir = TempDir::new();
+        std::fs::create_dir_all(dir.path()).unwrap();
+        let path = dir.join("media-sources.json");
+        std::fs::write(&path, "not json").unwrap();
+        std::fs::write(with_suffix(&path, "bak"), "not json either").unwrap();
+
+        let backend = FileBackend::new(dir.path()).await.unwrap();
+        assert!(backend.get_media_sources().await.unwrap().is_empty());
+        let corrupt = std::fs::read_to_string(with_suffix(&path, "corrupt")).unwrap();
+        assert_eq!(corrupt, "not json");
+    }
+
+    #[tokio::test]
+    async fn leftover_of_an_interrupted_write_is_removed() {
+        let dir = dir_with_backup().await;
+        let path = dir.join("media-sources.json");
+        std::fs::write(with_suffix(&path, "tmp"), "half of a wri").unwrap();
+
+        let backend = FileBackend::new(dir.path()).await.unwrap();
+        assert_eq!(backend.get_media_sources().await.unwrap().len(), 1);
+        assert!(!with_suffix(&path, "tmp").exists());
+        assert!(!with_suffix(&path, "corrupt").exists());
+    }
+
+    #[test]
+    fn every_version_has_a_migration() {
+        assert_eq!(MIGRATIONS.len() as u64, MEDIA_SOURCES_VERSION);
+    }
+
+    #[test]
+    fn wrap_in_envelope_upgrades_bare_array() {
+        let value = wrap_in_envelope(serde_json::from_str(BARE_ARRAY).unwrap()).unwrap();
+        assert_eq!(value["version"], 1);
+
+        let file: MediaSourcesFile = serde_json::from_value(value).unwrap();
+        assert_eq!(file.media_sources.len(), 1);
+        assert_eq!(file.media_sources[0].name, "Radio");
+        assert_eq!(file.media_sources[0].link, "http://radio.example/stream");
+        assert!(file.media_sources[0].default_source);
+    }
+
+    #[test]
+    fn assign_ids_numbers_sources_in_order() {
+        let sources: Value = serde_json::from_str(BARE_ARRAY).unwrap();
+        let value = assign_ids(wrap_in_envelope(json!([sources[0], sources[0]])).unwrap()).unwrap();
+        assert_eq!(value["version"], 2);
+
+        let file: MediaSourcesFile = serde_json::from_value(value).unwrap();
+        let ids: Vec<u64> = file.media_sources.iter().map(|src| src.id).collect();
+        assert_eq!(ids, [1, 2]);
+    }
+
+    #[test]
+    fn decode_reports_version_of_content() {
+        let path = Path::new("media-sources.json");
+        let (sources, version) = decode(path, BARE_ARRAY).unwrap();
+        assert_eq!((sources.len(), version), (1, 0));
+
+        let current = encode(sources).unwrap();
+        let (sources, version) = decode(path, std::str::from_utf8(&current).unwrap()).unwrap();
+        assert_eq!((sources.len(), version), (1, MEDIA_SOURCES_VERSION));
+
+        let (sources, version) = decode(path, "").unwrap();
+        assert_eq!((sources.len(), version), (0, MEDIA_SOURCES_VERSION));
+    }
+
+    #[test]
+    fn decode_rejects_newer_version() {
+        let content = json!({ "version": MEDIA_SOURCES_VERSION + 1, "media_sources": [] });
+        let result = decode(Path::new("media-sources.json"), &content.to_string());
+        assert!(matches!(
+            result,
+            Err(HomeRadioError::UnsupportedStateVersion { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn old_file_is_backed_up_and_upgraded_on_open() {
+        let dir = TempDir::new();
+        std::fs::create_dir_all(dir.path()).unwrap();
+        let path = dir.join("media-sources.json");
+        std::fs::write(&path, BARE_ARRAY).unwrap();
+
+        std::fs::write(dir.join("currently-playing"), "http://radio.example/stream").unwrap();
+
+        let backend = FileBackend::new(dir.path()).await.unwrap();
+        let sources = backend.get_media_sources().await.unwrap();
+        assert_eq!(sources.len(), 1);
+        assert_eq!(
+            backend.get_current_media_source().await.unwrap(),
+            Some(sources[0].id)
+        );
+        let backup = std::fs::read_to_string(dir.join("media-sources.json.v0.bak")).unwrap();
+        assert_eq!(backup, BARE_ARRAY);
+        let upgraded: Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(upgraded["version"], MEDIA_SOURCES_VERSION);
+    }
+
+    #[tokio::test]
+    async fn current_link_is_upgraded_with_version_1() {
+        let dir = TempDir::new();
+        std::fs::create_dir_all(dir.path()).unwrap();
+        let sources: Value = serde_json::from_str(BARE_ARRAY).unwrap();
+        let version_1 = json!({ "version": 1, "media_sources": [sources[0], sources[0]] });
+        std::fs::write(dir.join("media-sources.json"), version_1.to_string()).unwrap();
+        std::fs::write(dir.join("currently-playing"), "http://radio.example/stream").unwrap();
+
+        let backend = FileBackend::new(dir.path()).await.unwrap();
+        // the first source with the link
+        assert_eq!(backend.get_current_media_source().await.unwrap(), Some(1));
+        assert!(dir.join("media-sources.json.v1.bak").exists());
+    }
+
+    #[tokio::test]
+    async fn write_atomic_creates_the_file_with_the_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new();
+        std::fs::create_dir_all(dir.path()).unwrap();
+        let path = dir.join("secret");
+        // a leftover of an interrupted write must not pass on its permissions
+        std::fs::write(with_suffix(&path, "tmp"), "").unwrap();
+        std::fs::set_permissions(
+            with_suffix(&path, "tmp"),
+            std::fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+
+        write_atomic(&path, b"first", 0o600).await.unwrap();
+        write_atomic(&path, b"second", 0o600).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let backup_mode = std::fs::metadata(with_suffix(&path, "bak"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(backup_mode & 0o777, 0o600);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
     }
 }
@@ -1,19 +1,164 @@
 mod file_backend;
+mod monitor;
+mod sqlite_backend;
+mod watcher;
+use std::{collections::HashSet, path::Path, str::FromStr};
+
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::errors::HomeRadioError;
 
 pub use file_backend::*;
+pub use monitor::*;
+pub use sqlite_backend::*;
+pub use watcher::*;
+
+/// Persistent state of the radio. New kinds of state, e.g. a playback history or alarms,
+/// get their own methods here so every backend has to support them.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn get_media_sources(&self) -> Result<Vec<MediaSource>, HomeRadioError>;
+
+    /// Adds the source or replaces the one with the same id. A source without an id
+    /// replaces the one with the same name or gets the next free id.
+    async fn add_media_source(&self, source: MediaSource) -> Result<(), HomeRadioError>;
+
+    async fn get_volume(&self) -> Result<u16, HomeRadioError>;
+
+    async fn set_volume(&self, volume: u16) -> Result<(), HomeRadioError>;
+
+    /// Id of the media source that was playing last.
+    async fn get_current_media_source(&self) -> Result<Option<u64>, HomeRadioError>;
+
+    async fn set_current_media_source(&self, id: u64) -> Result<(), HomeRadioError>;
+
+    async fn remove_current_media_source(&self) -> Result<(), HomeRadioError>;
+}
+
+/// name of the database file of the sqlite backend inside the state dir
+pub const SQLITE_FILE: &str = "home-radio.sqlite";
+
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    /// one file per kind of state, see `FileBackend`
+    File,
+    Sqlite,
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
 
-#[derive(Deserialize, Serialize)]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(StorageKind::File),
+            "sqlite" => Ok(StorageKind::Sqlite),
+            _ => Err("expected file or sqlite".into()),
+        }
+    }
+}
+
+/// Opens the storage of the given kind in the state dir.
+pub async fn open(kind: StorageKind, dir: &Path) -> Result<Box<dyn Storage>, HomeRadioError> {
+    Ok(match kind {
+        StorageKind::File => Box::new(FileBackend::new(dir).await?),
+        StorageKind::Sqlite => Box::new(SqliteBackend::new(dir.join(SQLITE_FILE)).await?),
+    })
+}
+
+/// Copies everything from one storage into another, sources with a name that already exists in `to` are replaced.
+pub async fn import(from: &dyn Storage, to: &dyn Storage) -> Result<(), HomeRadioError> {
+    let sources = from.get_media_sources().await?;
+    let current = from
+        .get_current_media_source()
+        .await?
+        .and_then(|id| sources.iter().find(|src| src.id == id))
+        .map(|src| src.name.clone());
+    // the ids of `to` are kept, sources are matched by name
+    for mut source in sources {
+        source.id = 0;
+        to.add_media_source(source).await?;
+    }
+    to.set_volume(from.get_volume().await?).await?;
+    let imported = to.get_media_sources().await?;
+    match current.and_then(|name| imported.iter().find(|src| src.name == name)) {
+        Some(current) => to.set_current_media_source(current.id).await,
+        None => to.remove_current_media_source().await,
+    }
+}
+
+#[derive(Deserialize, Serialize, ToSchema, Clone, PartialEq)]
 pub struct MediaSource {
+    /// assigned by the storage, 0 for a source that is sent without one
+    #[serde(default)]
+    pub id: u64,
     pub link: String,
     pub name: String,
     pub media_type: MediaType,
     pub currently_playing: Option<bool>,
     pub default_source: bool,
+    /// mirrors or other bitrates of `link`, tried in order when `link` can't be played
+    #[serde(default)]
+    pub alternate_links: Vec<String>,
+    /// name of the source to switch to when none of the links can be played
+    #[serde(default)]
+    pub fallback_source: Option<String>,
+    /// the link that is actually playing, only set in responses
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    #[schema(read_only)]
+    pub active_link: Option<String>,
+    /// the name of the source that is actually playing, only set in responses
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    #[schema(read_only)]
+    pub active_source: Option<String>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, PartialEq, Debug)]
 pub enum MediaType {
     Radio,
     YouTube,
 }
+
+/// A single link that can be tried when starting playback.
+#[derive(Serialize, ToSchema, Clone, Debug, PartialEq)]
+pub struct PlaybackCandidate {
+    /// name of the media source the link belongs to, `None` for links that aren't stored
+    pub source: Option<String>,
+    pub link: String,
+}
+
+/// Returns the links to try in order when playback of the source `id` is requested:
+/// its link, its alternates, and then the links of the fallback sources.
+pub fn playback_candidates(sources: &[MediaSource], id: u64) -> Vec<PlaybackCandidate> {
+    let mut current = match sources.iter().find(|src| src.id == id) {
+        Some(src) => src,
+        None => return Vec::new(),
+    };
+
+    let mut candidates = Vec::new();
+    let mut visited = HashSet::new();
+    while visited.insert(&current.name[..]) {
+        for link in std::iter::once(&current.link).chain(current.alternate_links.iter()) {
+            candidates.push(PlaybackCandidate {
+                source: Some(current.name.clone()),
+                link: link.clone(),
+            });
+        }
+        let fallback = current
+            .fallback_source
+            .as_ref()
+            .and_then(|name| sources.iter().find(|src| &src.name == name));
+        match fallback {
+            Some(fallback) => current = fallback,
+            None => break,
+        }
+    }
+    candidates
+}
+
+/// The id a new source gets, one more than the highest id in use.
+pub fn next_id(sources: &[MediaSource]) -> u64 {
+    sources.iter().map(|src| src.id).max().unwrap_or(0) + 1
+}
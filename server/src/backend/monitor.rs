@@ -0,0 +1,194 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use serde::Serialize;
+use tokio::fs;
+use utoipa::ToSchema;
+
+use crate::{
+    errors::{ErrorReport, HomeRadioError},
+    media_service::unix_time,
+};
+
+use super::{
+    MediaSource, OwnWrites, Storage, StorageKind, CURRENT_FILE, MEDIA_SOURCES_FILE, SQLITE_FILE,
+    VOLUME_FILE,
+};
+
+/// Prefix of the files created and removed in the state dir to find out if it is writable.
+pub const WRITE_PROBE: &str = ".write-probe";
+
+/// the health endpoints are public, don't let them write to the sd card more often than this
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// tells apart the probes of concurrent requests
+static PROBE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Serialize, ToSchema, Clone)]
+pub struct StorageReport {
+    pub backend: StorageKind,
+    pub dir: String,
+    pub writable: bool,
+    /// failed reads and writes since the start
+    pub errors: u64,
+    pub last_error: Option<ErrorReport>,
+}
+
+struct StorageHealth {
+    errors: u64,
+    last_error: Option<ErrorReport>,
+    /// result and time of the last write probe
+    probed: Option<(Instant, bool)>,
+}
+
+/// Shares the health of a `MonitoredStorage` with the status endpoints.
+#[derive(Clone)]
+pub struct StorageHandle {
+    backend: StorageKind,
+    dir: PathBuf,
+    inner: Arc<Mutex<StorageHealth>>,
+    own_writes: OwnWrites,
+}
+
+impl StorageHandle {
+    pub async fn report(&self) -> StorageReport {
+        let writable = self.writable().await;
+        let health = self.inner.lock().unwrap();
+        StorageReport {
+            backend: self.backend,
+            dir: self.dir.to_string_lossy().into(),
+            writable,
+            errors: health.errors,
+            last_error: health.last_error.clone(),
+        }
+    }
+
+    /// Failed reads and writes since the start.
+    pub fn errors(&self) -> u64 {
+        self.inner.lock().unwrap().errors
+    }
+
+    /// Creates and removes a probe file in the state dir, at most once per `PROBE_INTERVAL`.
+    pub async fn writable(&self) -> bool {
+        if let Some((at, writable)) = self.inner.lock().unwrap().probed {
+            if at.elapsed() < PROBE_INTERVAL {
+                return writable;
+            }
+        }
+        let probe = self.dir.join(format!(
+            "{}-{}-{}",
+            WRITE_PROBE,
+            std::process::id(),
+            PROBE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let written = fs::write(&probe, b"").await;
+        let removed = fs::remove_file(&probe).await;
+        let writable = written.is_ok() && removed.is_ok();
+        self.inner.lock().unwrap().probed = Some((Instant::now(), writable));
+        writable
+    }
+
+    /// The writes of the storage, for the `StateWatcher`.
+    pub fn own_writes(&self) -> OwnWrites {
+        self.own_writes.clone()
+    }
+
+    /// Records the state file of either backend that holds what was written.
+    async fn wrote<T>(
+        &self,
+        file: &str,
+        result: Result<T, HomeRadioError>,
+    ) -> Result<T, HomeRadioError> {
+        let result = self.track(result);
+        let file = match self.backend {
+            StorageKind::File => file,
+            StorageKind::Sqlite => SQLITE_FILE,
+        };
+        self.own_writes.record(&self.dir, &[file]).await;
+        result
+    }
+
+    fn track<T>(&self, result: Result<T, HomeRadioError>) -> Result<T, HomeRadioError> {
+        if let Err(e) = &result {
+            let mut health = self.inner.lock().unwrap();
+            health.errors += 1;
+            health.last_error = Some(ErrorReport {
+                timestamp: unix_time(),
+                component: "storage".into(),
+                message: e.to_string(),
+            });
+        }
+        result
+    }
+}
+
+/// Wraps a storage and counts its errors.
+pub struct MonitoredStorage {
+    storage: Box<dyn Storage>,
+    handle: StorageHandle,
+}
+
+impl MonitoredStorage {
+    pub fn new(storage: Box<dyn Storage>, backend: StorageKind, dir: &Path) -> Self {
+        MonitoredStorage {
+            storage,
+            handle: StorageHandle {
+                backend,
+                dir: dir.to_path_buf(),
+                inner: Arc::new(Mutex::new(StorageHealth {
+                    errors: 0,
+                    last_error: None,
+                    probed: None,
+                })),
+                own_writes: OwnWrites::default(),
+            },
+        }
+    }
+
+    pub fn handle(&self) -> StorageHandle {
+        self.handle.clone()
+    }
+}
+
+#[async_trait]
+impl Storage for MonitoredStorage {
+    async fn get_media_sources(&self) -> Result<Vec<MediaSource>, HomeRadioError> {
+        self.handle.track(self.storage.get_media_sources().await)
+    }
+
+    async fn add_media_source(&self, source: MediaSource) -> Result<(), HomeRadioError> {
+        let result = self.storage.add_media_source(source).await;
+        self.handle.wrote(MEDIA_SOURCES_FILE, result).await
+    }
+
+    async fn get_volume(&self) -> Result<u16, HomeRadioError> {
+        self.handle.track(self.storage.get_volume().await)
+    }
+
+    async fn set_volume(&self, volume: u16) -> Result<(), HomeRadioError> {
+        let result = self.storage.set_volume(volume).await;
+        self.handle.wrote(VOLUME_FILE, result).await
+    }
+
+    async fn get_current_media_source(&self) -> Result<Option<u64>, HomeRadioError> {
+        self.handle
+            .track(self.storage.get_current_media_source().await)
+    }
+
+    async fn set_current_media_source(&self, id: u64) -> Result<(), HomeRadioError> {
+        let result = self.storage.set_current_media_source(id).await;
+        self.handle.wrote(CURRENT_FILE, result).await
+    }
+
+    async fn remove_current_media_source(&self) -> Result<(), HomeRadioError> {
+        let result = self.storage.remove_current_media_source().await;
+        self.handle.wrote(CURRENT_FILE, result).await
+    }
+}
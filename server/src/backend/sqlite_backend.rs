@@ -0,0 +1,343 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+use tracing::info;
+
+use crate::errors::HomeRadioError;
+
+use super::{MediaSource, MediaType, Storage};
+
+/// Schema changes in the order they were introduced. Never change an existing entry, append a new one instead.
+/// The number of applied migrations is kept in the `user_version` pragma.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE media_sources (
+        name TEXT PRIMARY KEY NOT NULL,
+        link TEXT NOT NULL,
+        media_type TEXT NOT NULL,
+        default_source INTEGER NOT NULL,
+        alternate_links TEXT NOT NULL,
+        fallback_source TEXT
+    );
+    CREATE TABLE settings (
+        key TEXT PRIMARY KEY NOT NULL,
+        value TEXT NOT NULL
+    );
+",
+    // sources get ids, numbered in the order they were added, and the current source is recorded by id
+    "
+    CREATE TABLE media_sources_v2 (
+        id INTEGER PRIMARY KEY NOT NULL,
+        name TEXT UNIQUE NOT NULL,
+        link TEXT NOT NULL,
+        media_type TEXT NOT NULL,
+        default_source INTEGER NOT NULL,
+        alternate_links TEXT NOT NULL,
+        fallback_source TEXT
+    );
+    INSERT INTO media_sources_v2
+        (id, name, link, media_type, default_source, alternate_links, fallback_source)
+    SELECT ROW_NUMBER() OVER (ORDER BY rowid),
+        name, link, media_type, default_source, alternate_links, fallback_source
+    FROM media_sources;
+    DELETE FROM settings
+    WHERE key = 'current_media_source' AND value NOT IN (SELECT link FROM media_sources_v2);
+    UPDATE settings
+    SET value = (SELECT MIN(id) FROM media_sources_v2 WHERE link = settings.value)
+    WHERE key = 'current_media_source';
+    DROP TABLE media_sources;
+    ALTER TABLE media_sources_v2 RENAME TO media_sources;
+",
+];
+
+const VOLUME_KEY: &str = "volume";
+const CURRENT_SOURCE_KEY: &str = "current_media_source";
+
+pub struct SqliteBackend {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteBackend {
+    pub async fn new<P: AsRef<Path>>(path: P) -> Result<Self, HomeRadioError> {
+        let path = path.as_ref().to_path_buf();
+        info!("using database {}", &path.to_string_lossy());
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let conn = tokio::task::spawn_blocking(move || {
+            let mut conn = Connection::open(&path)?;
+            migrate(&mut conn)?;
+            Ok::<_, rusqlite::Error>(conn)
+        })
+        .await??;
+        Ok(SqliteBackend {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs `f` on the blocking thread pool since rusqlite has no async api.
+    async fn with_connection<T, F>(&self, f: F) -> Result<T, HomeRadioError>
+    where
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let conn = self.conn.clone();
+        Ok(tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap())).await??)
+    }
+
+    async fn get_setting(&self, key: &'static str) -> Result<Option<String>, HomeRadioError> {
+        self.with_connection(move |conn| {
+            conn.query_row("SELECT value FROM settings WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })
+            .optional()
+        })
+        .await
+    }
+
+    async fn set_setting(&self, key: &'static str, value: String) -> Result<(), HomeRadioError> {
+        self.with_connection(move |conn| {
+            conn.execute(
+                "INSERT INTO settings (key, value) VALUES (?1, ?2)
+                 ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+                [key, &value],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        info!("migrating database to version {}", i + 1);
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", (i + 1) as i64)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for SqliteBackend {
+    async fn get_media_sources(&self) -> Result<Vec<MediaSource>, HomeRadioError> {
+        let rows = self
+            .with_connection(|conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, name, link, media_type, default_source, alternate_links, fallback_source
+                     FROM media_sources ORDER BY id",
+                )?;
+                let rows = stmt.query_map([], |row| {
+                    Ok((
+                        row.get::<_, u64>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, String>(3)?,
+                        row.get::<_, bool>(4)?,
+                        row.get::<_, String>(5)?,
+                        row.get::<_, Option<String>>(6)?,
+                    ))
+                })?;
+                rows.collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .await?;
+
+        let mut sources = Vec::new();
+        for (id, name, link, media_type, default_source, alternate_links, fallback_source) in rows {
+            sources.push(MediaSource {
+                id,
+                link,
+                name,
+                media_type: serde_json::from_value(serde_json::Value::String(media_type))?,
+                currently_playing: None,
+                default_source,
+                alternate_links: serde_json::from_str(&alternate_links)?,
+                fallback_source,
+                active_link: None,
+                active_source: None,
+            });
+        }
+        Ok(sources)
+    }
+
+    async fn add_media_source(&self, source: MediaSource) -> Result<(), HomeRadioError> {
+        let media_type = match source.media_type {
+            MediaType::Radio => "Radio",
+            MediaType::YouTube => "YouTube",
+        };
+        let alternate_links = serde_json::to_string(&source.alternate_links)?;
+        self.with_connection(move |conn| {
+            let tx = conn.transaction()?;
+            let id = match source.id {
+                0 => tx
+                    .query_row(
+                        "SELECT id FROM media_sources WHERE name = ?1",
+                        [&source.name],
+                        |row| row.get(0),
+                    )
+                    .optional()?,
+                id => Some(id),
+            };
+            let id: u64 = match id {
+                Some(id) => id,
+                None => tx.query_row(
+                    "SELECT COALESCE(MAX(id), 0) + 1 FROM media_sources",
+                    [],
+                    |row| row.get(0),
+                )?,
+            };
+            tx.execute(
+                "INSERT INTO media_sources
+                    (id, name, link, media_type, default_source, alternate_links, fallback_source)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                 ON CONFLICT(id) DO UPDATE SET
+                    name = excluded.name,
+                    link = excluded.link,
+                    media_type = excluded.media_type,
+                    default_source = excluded.default_source,
+                    alternate_links = excluded.alternate_links,
+                    fallback_source = excluded.fallback_source",
+                params![
+                    id,
+                    source.name,
+                    source.link,
+                    media_type,
+                    source.default_source,
+                    alternate_links,
+                    source.fallback_source,
+                ],
+            )?;
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn get_volume(&self) -> Result<u16, HomeRadioError> {
+        match self.get_setting(VOLUME_KEY).await? {
+            Some(volume) => Ok(volume.parse()?),
+            None => Ok(100),
+        }
+    }
+
+    async fn set_volume(&self, volume: u16) -> Result<(), HomeRadioError> {
+        self.set_setting(VOLUME_KEY, volume.to_string()).await
+    }
+
+    async fn get_current_media_source(&self) -> Result<Option<u64>, HomeRadioError> {
+        match self.get_setting(CURRENT_SOURCE_KEY).await? {
+            Some(id) => Ok(Some(id.parse()?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn set_current_media_source(&self, id: u64) -> Result<(), HomeRadioError> {
+        self.set_setting(CURRENT_SOURCE_KEY, id.to_string()).await
+    }
+
+    async fn remove_current_media_source(&self) -> Result<(), HomeRadioError> {
+        self.with_connection(|conn| {
+            conn.execute("DELETE FROM settings WHERE key = ?1", [CURRENT_SOURCE_KEY])?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::SQLITE_FILE,
+        test_support::{source, TempDir},
+    };
+
+    fn user_version(path: &Path) -> i64 {
+        Connection::open(path)
+            .unwrap()
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn state_survives_a_reopen() {
+        let dir = TempDir::new();
+        let path = dir.join(SQLITE_FILE);
+        let backend = SqliteBackend::new(&path).await.unwrap();
+        assert_eq!(backend.get_volume().await.unwrap(), 100);
+
+        let mut radio = source("Radio", "http://radio.example/stream");
+        radio.default_source = true;
+        radio.alternate_links = vec!["http://radio.example/low".into()];
+        radio.fallback_source = Some("Other".into());
+        backend.add_media_source(radio.clone()).await.unwrap();
+        backend
+            .add_media_source(source("Other", "http://other.example/stream"))
+            .await
+            .unwrap();
+        // a source without an id replaces the one with its name
+        backend
+            .add_media_source(source("Other", "http://other.example/new"))
+            .await
+            .unwrap();
+        backend.set_volume(42).await.unwrap();
+        backend.set_current_media_source(2).await.unwrap();
+        drop(backend);
+
+        let backend = SqliteBackend::new(&path).await.unwrap();
+        let sources = backend.get_media_sources().await.unwrap();
+        radio.id = 1;
+        assert_eq!(sources.len(), 2);
+        assert!(sources[0] == radio);
+        assert_eq!(sources[1].id, 2);
+        assert_eq!(sources[1].link, "http://other.example/new");
+        assert_eq!(backend.get_volume().await.unwrap(), 42);
+        assert_eq!(backend.get_current_media_source().await.unwrap(), Some(2));
+
+        backend.remove_current_media_source().await.unwrap();
+        assert_eq!(backend.get_current_media_source().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn empty_database_is_migrated_from_user_version_0() {
+        let dir = TempDir::new();
+        let path = dir.join(SQLITE_FILE);
+        SqliteBackend::new(&path).await.unwrap();
+
+        assert_eq!(user_version(&path), MIGRATIONS.len() as i64);
+    }
+
+    #[tokio::test]
+    async fn first_schema_is_migrated_to_ids() {
+        let dir = TempDir::new();
+        let path = dir.join(SQLITE_FILE);
+        std::fs::create_dir_all(dir.path()).unwrap();
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(MIGRATIONS[0]).unwrap();
+            conn.execute_batch(
+                "INSERT INTO media_sources VALUES ('One', 'http://one.example', 'Radio', 0, '[]', NULL);
+                 INSERT INTO media_sources VALUES ('Two', 'http://two.example', 'Radio', 1, '[]', 'One');
+                 INSERT INTO settings VALUES ('current_media_source', 'http://two.example');
+                 INSERT INTO settings VALUES ('volume', '80');
+                 PRAGMA user_version = 1;",
+            )
+            .unwrap();
+        }
+
+        let backend = SqliteBackend::new(&path).await.unwrap();
+        let sources = backend.get_media_sources().await.unwrap();
+        let names: Vec<(u64, &str)> = sources.iter().map(|src| (src.id, &src.name[..])).collect();
+        assert_eq!(names, [(1, "One"), (2, "Two")]);
+        assert_eq!(sources[1].fallback_source.as_deref(), Some("One"));
+        assert_eq!(backend.get_current_media_source().await.unwrap(), Some(2));
+        assert_eq!(backend.get_volume().await.unwrap(), 80);
+        drop(backend);
+        assert_eq!(user_version(&path), MIGRATIONS.len() as i64);
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::{fs, sync::mpsc, time::sleep};
+use tracing::{debug, info};
+
+use crate::errors::HomeRadioError;
+
+use super::{CURRENT_FILE, MEDIA_SOURCES_FILE, SQLITE_FILE, VOLUME_FILE};
+
+/// editors and the storage itself touch files several times in a row, wait until it's quiet
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// The files of both backends that hold the state, changes to backups, journals,
+/// the auth store or the vlc config don't need a reload.
+const STATE_FILES: &[&str] = &[MEDIA_SOURCES_FILE, VOLUME_FILE, CURRENT_FILE, SQLITE_FILE];
+
+/// Modification time and size of a state file right after the storage wrote it, `None` if it removed it.
+type Stamp = Option<(SystemTime, u64)>;
+
+/// Remembers the state files as the storage left them, so the `StateWatcher` can tell its
+/// own writes apart from changes made by hand.
+#[derive(Clone, Default)]
+pub struct OwnWrites {
+    stamps: Arc<Mutex<HashMap<PathBuf, Stamp>>>,
+}
+
+impl OwnWrites {
+    /// Records `files` in `dir` after the storage wrote them.
+    pub async fn record(&self, dir: &Path, files: &[&str]) {
+        for file in files {
+            let path = dir.join(file);
+            let stamp = stamp(&path).await;
+            self.stamps.lock().unwrap().insert(path, stamp);
+        }
+    }
+
+    async fn is_own(&self, path: &Path) -> bool {
+        let stamp = stamp(path).await;
+        self.stamps.lock().unwrap().get(path) == Some(&stamp)
+    }
+}
+
+async fn stamp(path: &Path) -> Stamp {
+    let meta = fs::metadata(path).await.ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+/// Notices changes to the state files, e.g. a media-sources.json edited by hand.
+pub struct StateWatcher {
+    // stops watching when dropped
+    _watcher: RecommendedWatcher,
+    events: mpsc::UnboundedReceiver<PathBuf>,
+    own_writes: OwnWrites,
+}
+
+impl StateWatcher {
+    pub fn new(dir: &Path, own_writes: OwnWrites) -> Result<Self, HomeRadioError> {
+        let (sender, events) = mpsc::unbounded_channel();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let event = match event {
+                    Ok(event) if !matches!(event.kind, EventKind::Access(_)) => event,
+                    _ => return,
+                };
+                // an atomic write is a rename of the temporary file to the state file
+                for path in event.paths {
+                    let name = path.file_name().and_then(|name| name.to_str());
+                    if name.is_some_and(|name| STATE_FILES.contains(&name)) {
+                        let _ = sender.send(path);
+                    }
+                }
+            })?;
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        info!("watching {} for changes", &dir.to_string_lossy());
+        Ok(StateWatcher {
+            _watcher: watcher,
+            events,
+            own_writes,
+        })
+    }
+
+    /// Waits for the next batch of changes that weren't written by the storage itself,
+    /// returns the changed files or `None` once the watcher stopped.
+    pub async fn changed(&mut self) -> Option<Vec<PathBuf>> {
+        loop {
+            let mut paths = HashSet::from([self.events.recv().await?]);
+            loop {
+                sleep(SETTLE_TIME).await;
+                let mut more = false;
+                while let Ok(path) = self.events.try_recv() {
+                    paths.insert(path);
+                    more = true;
+                }
+                if !more {
+                    break;
+                }
+            }
+            let mut changed = Vec::new();
+            for path in paths {
+                if !self.own_writes.is_own(&path).await {
+                    changed.push(path);
+                }
+            }
+            if !changed.is_empty() {
+                changed.sort();
+                return Some(changed);
+            }
+            debug!("ignoring the changes written by the storage itself");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[tokio::test]
+    async fn only_changes_by_hand_to_state_files_are_reported() {
+        let dir = TempDir::new();
+        std::fs::create_dir_all(dir.path()).unwrap();
+        let own_writes = OwnWrites::default();
+        let mut watcher = StateWatcher::new(dir.path(), own_writes.clone()).unwrap();
+
+        std::fs::write(dir.join(VOLUME_FILE), "50").unwrap();
+        own_writes.record(dir.path(), &[VOLUME_FILE]).await;
+        std::fs::write(dir.join("volume.bak"), "40").unwrap();
+        std::fs::write(dir.join(MEDIA_SOURCES_FILE), "[]").unwrap();
+
+        let changed = timeout(Duration::from_secs(5), watcher.changed()).await;
+        assert_eq!(changed.unwrap(), Some(vec![dir.join(MEDIA_SOURCES_FILE)]));
+    }
+}
@@ -1,4 +1,4 @@
-use clap::{App, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, SubCommand};
 
 
 
@@ -6,18 +6,185 @@ pub fn build_app() -> App<'static,'static> {
     App::new("home-radio")
         .version("1.0.0")
         .author("Rene Richter")
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .help("path of the configuration file [default: /etc/home-radio/config.toml]")
+                .takes_value(true)
+                .global(true)
+        )
         .subcommand(
             SubCommand::with_name("serve")
-                    .arg(
-                        Arg::with_name("autoplay")
-                            .long("autoplay")
-                            .takes_value(false)
+                    .args(&config_args())
+        )
+        .subcommand(
+            SubCommand::with_name("storage")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        SubCommand::with_name("import")
+                            .about("imports the state files of the file storage into the configured storage")
+                            .arg(
+                                Arg::with_name("from")
+                                    .long("from")
+                                    .help("directory containing media-sources.json, volume and currently-playing")
+                                    .takes_value(true)
+                                    .required(true)
+                            )
+                            .args(&config_args())
+                    )
+        )
+        .subcommand(
+            SubCommand::with_name("user")
+                    .about("manages the users that can log in to the ui")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        SubCommand::with_name("add")
+                            .about("adds a user or changes the password and scope of an existing one")
+                            .arg(Arg::with_name("name").required(true))
+                            .arg(scope_arg())
+                            .arg(
+                                Arg::with_name("password-stdin")
+                                    .long("password-stdin")
+                                    .help("read the password from the first line of stdin instead of asking for it")
+                                    .takes_value(false)
+                            )
+                            .args(&config_args())
+                    )
+                    .subcommand(
+                        SubCommand::with_name("remove")
+                            .arg(Arg::with_name("name").required(true))
+                            .args(&config_args())
+                    )
+                    .subcommand(
+                        SubCommand::with_name("list")
+                            .args(&config_args())
+                    )
+        )
+        .subcommand(
+            SubCommand::with_name("token")
+                    .about("manages the api tokens for integrations")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        SubCommand::with_name("create")
+                            .about("creates a token and prints it, it can't be shown again")
+                            .arg(
+                                Arg::with_name("name")
+                                    .help("what the token is used for")
+                                    .required(true)
+                            )
+                            .arg(scope_arg())
+                            .args(&config_args())
+                    )
+                    .subcommand(
+                        SubCommand::with_name("revoke")
+                            .arg(
+                                Arg::with_name("id")
+                                    .help("id of the token as shown by `token list`")
+                                    .required(true)
+                            )
+                            .args(&config_args())
                     )
-                    .arg(
-                        Arg::with_name("dir")
-                            .long("dir")
-                            .takes_value(true)
-                            .default_value("/var/lib/home-radio")
+                    .subcommand(
+                        SubCommand::with_name("list")
+                            .args(&config_args())
                     )
         )
-}
\ No newline at end of file
+        .subcommand(
+            SubCommand::with_name("config")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        SubCommand::with_name("show")
+                            .about("prints the effective configuration")
+                            .args(&config_args())
+                    )
+        )
+}
+
+fn scope_arg() -> Arg<'static, 'static> {
+    Arg::with_name("scope")
+        .long("scope")
+        .help("read, control or admin, each one includes the ones before it")
+        .takes_value(true)
+        .possible_values(&["read", "control", "admin"])
+        .required(true)
+}
+
+/// Flags that override the configuration file and environment variables.
+/// Their defaults live in the configuration, so none of them has a clap default.
+fn config_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("bind")
+            .long("bind")
+            .help("address the web interface listens on, unless server.listeners is configured [default: 0.0.0.0:8080]")
+            .takes_value(true),
+        switch("autoplay"),
+        Arg::with_name("dir")
+            .long("dir")
+            .help("directory the state is stored in [default: /var/lib/home-radio]")
+            .takes_value(true),
+        Arg::with_name("storage")
+            .long("storage")
+            .help("where the state is stored, file or sqlite [default: file]")
+            .takes_value(true),
+        switch("watch-state")
+            .help("reload the state when the files in the state dir are edited"),
+        switch("adhoc-links")
+            .help("allow playing links that aren't stored as a media source, see playback.allowed_schemes and playback.allowed_hosts"),
+        switch("auth")
+            .help("require a login or an api token for everything but the ui files"),
+        switch("tls")
+            .help("serve https, with a self-signed certificate unless --tls-cert and --tls-key are given"),
+        Arg::with_name("tls-cert")
+            .long("tls-cert")
+            .help("pem certificate chain [default: tls/cert.pem in the state dir]")
+            .takes_value(true),
+        Arg::with_name("tls-key")
+            .long("tls-key")
+            .help("pem private key [default: tls/key.pem in the state dir]")
+            .takes_value(true),
+        Arg::with_name("log-level")
+            .long("log-level")
+            .help("log filter like info,actix_web=debug [default: info]")
+            .takes_value(true),
+        Arg::with_name("log-format")
+            .long("log-format")
+            .help("text or json [default: text]")
+            .takes_value(true),
+        switch("access-log")
+            .help("log every request, see logging.access_log_format"),
+        Arg::with_name("fade-out")
+            .long("fade-out")
+            .help("seconds to fade out the audio when shutting down, 0 keeps playing [default: 0]")
+            .takes_value(true),
+        Arg::with_name("vlc-binary")
+            .long("vlc-binary")
+            .help("[default: /usr/bin/vlc]")
+            .takes_value(true),
+        Arg::with_name("vlc-host")
+            .long("vlc-host")
+            .help("address the vlc http interface listens on [default: 127.0.0.1]")
+            .takes_value(true),
+        Arg::with_name("vlc-port")
+            .long("vlc-port")
+            .help("[default: 8090]")
+            .takes_value(true),
+        Arg::with_name("vlc-password")
+            .long("vlc-password")
+            .help("password of the vlc http interface, a random one is generated if omitted")
+            .takes_value(true),
+        switch("external-vlc")
+            .help("use an already running vlc instead of starting one"),
+    ]
+}
+
+/// A flag that switches a setting on, `--<name>=false` switches it off again,
+/// e.g. when the configuration file or the environment switched it on.
+fn switch(name: &'static str) -> Arg<'static, 'static> {
+    Arg::with_name(name)
+        .long(name)
+        .takes_value(true)
+        .min_values(0)
+        .max_values(1)
+        .require_equals(true)
+        .possible_values(&["true", "false"])
+}
@@ -0,0 +1,871 @@
+use std::{
+    env,
+    fmt::Display,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::ArgMatches;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::StorageKind,
+    errors::HomeRadioError,
+    listener::{ListenAddress, Listener},
+    logging::{self, LogFormat},
+    media_service::{
+        random_password, AdhocPolicy, PollerSettings, SupervisorSettings, VlcSettings,
+        WatchdogSettings,
+    },
+    tls::TlsSettings,
+};
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/home-radio/config.toml";
+const CONFIG_PATH_ENV: &str = "HOME_RADIO_CONFIG";
+const ENV_PREFIX: &str = "HOME_RADIO_";
+
+/// Settings that can also be overridden by command line flags, given as (configuration key, command line flag).
+/// Every setting can be overridden by an environment variable, the key in upper case with `HOME_RADIO_`
+/// in front, e.g. `HOME_RADIO_SERVER_BIND`.
+const OVERRIDES: &[(&str, &str)] = &[
+    ("server.bind", "bind"),
+    ("server.dir", "dir"),
+    ("server.autoplay", "autoplay"),
+    ("storage.backend", "storage"),
+    ("storage.watch", "watch-state"),
+    ("playback.adhoc_links", "adhoc-links"),
+    ("auth.enabled", "auth"),
+    ("tls.enabled", "tls"),
+    ("tls.cert", "tls-cert"),
+    ("tls.key", "tls-key"),
+    ("logging.level", "log-level"),
+    ("logging.format", "log-format"),
+    ("logging.access_log", "access-log"),
+    ("shutdown.fade_out_secs", "fade-out"),
+    ("vlc.binary", "vlc-binary"),
+    ("vlc.host", "vlc-host"),
+    ("vlc.port", "vlc-port"),
+    ("vlc.password", "vlc-password"),
+    ("vlc.external", "external-vlc"),
+];
+
+/// The server configuration, layered from the configuration file, environment variables and command line flags.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub playback: PlaybackConfig,
+    pub auth: AuthConfig,
+    pub tls: TlsConfig,
+    pub logging: LoggingConfig,
+    pub vlc: VlcConfig,
+    pub watchdog: WatchdogConfig,
+    pub supervisor: SupervisorConfig,
+    pub reconcile: ReconcileConfig,
+    pub status: StatusConfig,
+    pub shutdown: ShutdownConfig,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// the only listener, serving the ui and the api, if `listeners` is empty
+    pub bind: String,
+    pub dir: PathBuf,
+    pub autoplay: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub listeners: Vec<ListenerConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: "0.0.0.0:8080".into(),
+            dir: "/var/lib/home-radio".into(),
+            autoplay: false,
+            listeners: Vec::new(),
+        }
+    }
+}
+
+/// A `[[server.listeners]]` entry, either `bind` or `socket` has to be set.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// tcp address like `0.0.0.0:8080` or `[::]:8080`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bind: Option<String>,
+    /// path of a unix socket
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub socket: Option<PathBuf>,
+    /// permissions of the socket file in octal, e.g. `660` to allow the group
+    pub socket_mode: String,
+    /// serve the web interface, it calls the api on the same address
+    pub ui: bool,
+    /// serve the control api under /api/v1 and the legacy routes
+    pub api: bool,
+    /// serve https on a tcp listener, `tls.enabled` if omitted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<bool>,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        ListenerConfig {
+            bind: None,
+            socket: None,
+            socket_mode: "600".into(),
+            ui: true,
+            api: true,
+            tls: None,
+        }
+    }
+}
+
+impl ListenerConfig {
+    fn resolve(&self, tls_enabled: bool) -> Result<Listener, String> {
+        let address = match (&self.bind, &self.socket) {
+            (Some(bind), None) => ListenAddress::Tcp(
+                bind.parse()
+                    .map_err(|_| format!("'{}' is not an address like 0.0.0.0:8080", bind))?,
+            ),
+            (None, Some(path)) => {
+                if self.tls == Some(true) {
+                    return Err(format!(
+                        "{} is a unix socket, it can't serve https",
+                        path.display()
+                    ));
+                }
+                let mode = u32::from_str_radix(&self.socket_mode, 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o777)
+                    .ok_or_else(|| {
+                        format!(
+                            "socket_mode '{}' is not an octal mode like 660",
+                            self.socket_mode
+                        )
+                    })?;
+                ListenAddress::Unix {
+                    path: path.clone(),
+                    mode,
+                }
+            }
+            _ => return Err("needs either bind or socket".into()),
+        };
+        if !self.ui && !self.api {
+            return Err("serves neither the ui nor the api".into());
+        }
+        let tls = matches!(address, ListenAddress::Tcp(_)) && self.tls.unwrap_or(tls_enabled);
+        Ok(Listener {
+            address,
+            ui: self.ui,
+            api: self.api,
+            tls,
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// `file` keeps the state in plain files in `server.dir`, `sqlite` in a database in `server.dir`
+    pub backend: StorageKind,
+    /// pick up changes to the state dir that were made while the server is running
+    pub watch: bool,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: StorageKind::File,
+            watch: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlaybackConfig {
+    /// allow playing links that aren't stored as a media source
+    pub adhoc_links: bool,
+    /// schemes an ad-hoc link may use
+    pub allowed_schemes: Vec<String>,
+    /// hosts an ad-hoc link may point to, `*.example.com` includes the subdomains, empty allows every host
+    pub allowed_hosts: Vec<String>,
+}
+
+impl Default for PlaybackConfig {
+    fn default() -> Self {
+        PlaybackConfig {
+            adhoc_links: false,
+            allowed_schemes: vec!["http".into(), "https".into()],
+            allowed_hosts: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// require a login or an api token for everything but the ui files, see `home-radio user` and `home-radio token`
+    pub enabled: bool,
+    /// how long a login to the ui stays valid
+    pub session_ttl_hours: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            enabled: false,
+            session_ttl_hours: 24 * 7,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// serve https instead of http on `server.bind`
+    pub enabled: bool,
+    /// pem certificate chain, `tls/cert.pem` in `server.dir` if omitted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cert: Option<PathBuf>,
+    /// pem private key, `tls/key.pem` in `server.dir` if omitted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<PathBuf>,
+    /// generate a self-signed certificate on the first start if neither file exists
+    pub self_signed: bool,
+    /// names and addresses the self-signed certificate is valid for
+    pub hostnames: Vec<String>,
+    /// how often the files are checked for a renewed certificate
+    pub reload_interval_secs: u64,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            enabled: false,
+            cert: None,
+            key: None,
+            self_signed: true,
+            hostnames: vec!["localhost".into()],
+            reload_interval_secs: 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// filter like `info,actix_web=debug`, can be changed at runtime with `PUT /api/v1/logging`
+    pub level: String,
+    pub format: LogFormat,
+    /// log every request, the lines have the target `actix_web::middleware::logger`
+    pub access_log: bool,
+    /// see the format of the `Logger` middleware of actix-web
+    pub access_log_format: String,
+    /// number of records kept in memory for `GET /logs`, 0 turns it off
+    pub buffer_size: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "info".into(),
+            format: LogFormat::Text,
+            access_log: false,
+            access_log_format: r#"%a "%r" %s %b "%{User-Agent}i" %T"#.into(),
+            buffer_size: 1000,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct VlcConfig {
+    pub binary: String,
+    pub host: String,
+    pub port: u16,
+    /// a random password is generated on every start if omitted,
+    /// a spawned vlc reads it from `vlcrc` in the state dir, which only the owner may read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    pub external: bool,
+    /// passed to a spawned vlc in addition to the arguments needed for the http interface
+    pub extra_args: Vec<String>,
+    pub startup_timeout_secs: u64,
+    /// how long all links and fallbacks of a source may take together
+    pub play_timeout_secs: u64,
+    pub health_retries: u16,
+    pub health_interval_millis: u16,
+}
+
+impl Default for VlcConfig {
+    fn default() -> Self {
+        VlcConfig {
+            binary: "/usr/bin/vlc".into(),
+            host: "127.0.0.1".into(),
+            port: 8090,
+            password: None,
+            external: false,
+            extra_args: Vec::new(),
+            startup_timeout_secs: 30,
+            play_timeout_secs: 90,
+            health_retries: 20,
+            health_interval_millis: 200,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchdogConfig {
+    pub poll_interval_secs: u64,
+    pub stall_polls: u32,
+    pub initial_backoff_secs: u64,
+    pub max_backoff_secs: u64,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig {
+            poll_interval_secs: 5,
+            stall_polls: 3,
+            initial_backoff_secs: 1,
+            max_backoff_secs: 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SupervisorConfig {
+    pub initial_backoff_secs: u64,
+    pub max_backoff_secs: u64,
+    pub stable_after_secs: u64,
+    /// how long vlc gets to quit after SIGTERM before it is killed
+    pub stop_timeout_secs: u64,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        SupervisorConfig {
+            initial_backoff_secs: 1,
+            max_backoff_secs: 60,
+            stable_after_secs: 60,
+            stop_timeout_secs: 5,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconcileConfig {
+    /// how often the stored state is compared with the player
+    pub interval_secs: u64,
+}
+
+impl Default for ReconcileConfig {
+    fn default() -> Self {
+        ReconcileConfig { interval_secs: 30 }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatusConfig {
+    /// poll interval while playback changes or someone waits for it to change
+    pub fast_interval_millis: u64,
+    /// poll interval the poller slows down to while nothing changes
+    pub slow_interval_millis: u64,
+}
+
+impl Default for StatusConfig {
+    fn default() -> Self {
+        StatusConfig {
+            fast_interval_millis: 500,
+            slow_interval_millis: 5000,
+        }
+    }
+}
+
+/// What happens on SIGTERM and SIGINT.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// how long open requests get to finish, open event streams are closed after it
+    pub timeout_secs: u64,
+    /// lower the volume over this many seconds and stop playback before exiting, 0 turns it off
+    pub fade_out_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            timeout_secs: 5,
+            fade_out_secs: 0,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration file given by `--config`, `HOME_RADIO_CONFIG` or the default path
+    /// and applies the environment variables and command line flags on top of it.
+    pub fn load(args: &ArgMatches) -> Result<Self, HomeRadioError> {
+        let path = args
+            .value_of("config")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os(CONFIG_PATH_ENV).map(PathBuf::from));
+        let mut config = match path {
+            Some(path) => Config::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Config::from_file(DEFAULT_CONFIG_PATH)?
+            }
+            None => Config::default(),
+        };
+
+        let mut vars: Vec<(String, String)> = env::vars()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX) && name != CONFIG_PATH_ENV)
+            .filter(|(name, _)| !OVERRIDES.iter().any(|(key, _)| env_name(key) == *name))
+            .collect();
+        vars.sort();
+        for (name, value) in vars {
+            config = config.with_env(&name, &value)?;
+        }
+        for (key, flag) in OVERRIDES {
+            if let Ok(value) = env::var(env_name(key)) {
+                config.set(key, &value)?;
+            }
+            if args.is_present(flag) {
+                // a switch without a value turns a setting on, `--flag=false` turns it off
+                config.set(key, args.value_of(flag).unwrap_or("true"))?;
+            }
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, HomeRadioError> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            HomeRadioError::InvalidConfig(format!("could not read {}: {}", path.display(), e))
+        })?;
+        toml::from_str(&content).map_err(|e| {
+            HomeRadioError::InvalidConfig(format!("could not parse {}: {}", path.display(), e))
+        })
+    }
+
+    /// Applies an environment variable of a setting without a command line flag. The value is read
+    /// as toml unless the setting is a string, e.g. `HOME_RADIO_VLC_EXTRA_ARGS='["--no-video"]'`.
+    fn with_env(&self, name: &str, value: &str) -> Result<Self, HomeRadioError> {
+        let invalid =
+            |problem: String| HomeRadioError::InvalidConfig(format!("{}: {}", name, problem));
+        let mut config = toml::Value::try_from(self).map_err(|e| invalid(e.to_string()))?;
+        let name_in_section = &name[ENV_PREFIX.len()..];
+        let (section, key) = config
+            .as_table_mut()
+            .into_iter()
+            .flat_map(|sections| sections.iter_mut())
+            .find_map(|(section, table)| {
+                let key = name_in_section
+                    .strip_prefix(&section.to_uppercase())?
+                    .strip_prefix('_')?;
+                Some((table.as_table_mut()?, key.to_lowercase()))
+            })
+            .ok_or_else(|| invalid("not a setting of any configuration section".into()))?;
+        let value = match section.get(&key) {
+            Some(toml::Value::String(_)) => toml::Value::String(value.into()),
+            _ => toml::from_str::<toml::value::Table>(&format!("value = {}", value))
+                .ok()
+                .and_then(|mut parsed| parsed.remove("value"))
+                .unwrap_or_else(|| toml::Value::String(value.into())),
+        };
+        section.insert(key, value);
+        config.try_into().map_err(|e| invalid(e.to_string()))
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), HomeRadioError> {
+        match key {
+            "server.bind" => self.server.bind = value.into(),
+            "server.dir" => self.server.dir = value.into(),
+            "server.autoplay" => self.server.autoplay = parse(key, value)?,
+            "storage.backend" => self.storage.backend = parse(key, value)?,
+            "storage.watch" => self.storage.watch = parse(key, value)?,
+            "playback.adhoc_links" => self.playback.adhoc_links = parse(key, value)?,
+            "auth.enabled" => self.auth.enabled = parse(key, value)?,
+            "tls.enabled" => self.tls.enabled = parse(key, value)?,
+            "tls.cert" => self.tls.cert = Some(value.into()),
+            "tls.key" => self.tls.key = Some(value.into()),
+            "logging.level" => self.logging.level = value.into(),
+            "logging.format" => self.logging.format = parse(key, value)?,
+            "logging.access_log" => self.logging.access_log = parse(key, value)?,
+            "shutdown.fade_out_secs" => self.shutdown.fade_out_secs = parse(key, value)?,
+            "vlc.binary" => self.vlc.binary = value.into(),
+            "vlc.host" => self.vlc.host = value.into(),
+            "vlc.port" => self.vlc.port = parse(key, value)?,
+            "vlc.password" => self.vlc.password = Some(value.into()),
+            "vlc.external" => self.vlc.external = parse(key, value)?,
+            _ => unreachable!("unknown configuration key {}", key),
+        }
+        Ok(())
+    }
+
+    /// Checks the settings that can't be enforced by their types and reports all problems at once.
+    fn validate(&self) -> Result<(), HomeRadioError> {
+        let mut problems = Vec::new();
+        if self.server.bind.parse::<SocketAddr>().is_err() {
+            problems.push(format!(
+                "server.bind: '{}' is not an address like 0.0.0.0:8080",
+                self.server.bind
+            ));
+        }
+        for (i, listener) in self.server.listeners.iter().enumerate() {
+            if let Err(problem) = listener.resolve(self.tls.enabled) {
+                problems.push(format!("server.listeners[{}] {}", i, problem));
+            }
+        }
+        if self.playback.adhoc_links && self.playback.allowed_schemes.is_empty() {
+            problems.push(
+                "playback.allowed_schemes must not be empty when playback.adhoc_links is set"
+                    .into(),
+            );
+        }
+        if self.auth.session_ttl_hours == 0 {
+            problems.push("auth.session_ttl_hours must be greater than 0".into());
+        }
+        if self.tls.cert.is_some() != self.tls.key.is_some() {
+            problems.push("tls.cert and tls.key must be given together".into());
+        }
+        if self.tls.self_signed && self.tls.hostnames.is_empty() {
+            problems.push("tls.hostnames must not be empty when tls.self_signed is set".into());
+        }
+        if self.tls.reload_interval_secs == 0 {
+            problems.push("tls.reload_interval_secs must be greater than 0".into());
+        }
+        if self.logging.level.trim().is_empty() {
+            problems.push("logging.level must not be empty".into());
+        } else if let Err(problem) = logging::parse_filter(&self.logging.level) {
+            problems.push(format!("logging.level: {}", problem));
+        }
+        if self.vlc.host.is_empty() {
+            problems.push("vlc.host must not be empty".into());
+        }
+        if self.vlc.port == 0 {
+            problems.push("vlc.port must not be 0".into());
+        }
+        if self.vlc.external && self.vlc.password.is_none() {
+            problems.push("vlc.password is required when vlc.external is set".into());
+        }
+        if self.vlc.startup_timeout_secs == 0 {
+            problems.push("vlc.startup_timeout_secs must be greater than 0".into());
+        }
+        if self.vlc.play_timeout_secs == 0 {
+            problems.push("vlc.play_timeout_secs must be greater than 0".into());
+        }
+        if self.vlc.health_retries == 0 {
+            problems.push("vlc.health_retries must be greater than 0".into());
+        }
+        if self.watchdog.poll_interval_secs == 0 {
+            problems.push("watchdog.poll_interval_secs must be greater than 0".into());
+        }
+        if self.watchdog.stall_polls == 0 {
+            problems.push("watchdog.stall_polls must be greater than 0".into());
+        }
+        // a backoff of 0 never grows and the retries would spin against vlc
+        if self.watchdog.initial_backoff_secs == 0 {
+            problems.push("watchdog.initial_backoff_secs must be greater than 0".into());
+        }
+        if self.watchdog.max_backoff_secs < self.watchdog.initial_backoff_secs {
+            problems.push(
+                "watchdog.max_backoff_secs must not be smaller than watchdog.initial_backoff_secs"
+                    .into(),
+            );
+        }
+        if self.reconcile.interval_secs == 0 {
+            problems.push("reconcile.interval_secs must be greater than 0".into());
+        }
+        if self.status.fast_interval_millis == 0 {
+            problems.push("status.fast_interval_millis must be greater than 0".into());
+        }
+        if self.status.slow_interval_millis < self.status.fast_interval_millis {
+            problems.push(
+                "status.slow_interval_millis must not be smaller than status.fast_interval_millis"
+                    .into(),
+            );
+        }
+        if self.supervisor.initial_backoff_secs == 0 {
+            problems.push("supervisor.initial_backoff_secs must be greater than 0".into());
+        }
+        if self.supervisor.max_backoff_secs < self.supervisor.initial_backoff_secs {
+            problems.push(
+                "supervisor.max_backoff_secs must not be smaller than supervisor.initial_backoff_secs"
+                    .into(),
+            );
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(HomeRadioError::InvalidConfig(problems.join("; ")))
+        }
+    }
+
+    /// Renders the configuration as toml with secrets masked.
+    pub fn to_toml(&self) -> Result<String, HomeRadioError> {
+        let mut shown = self.clone();
+        if shown.vlc.password.is_some() {
+            shown.vlc.password = Some("********".into());
+        }
+        toml::to_string_pretty(&shown).map_err(|e| HomeRadioError::InvalidConfig(e.to_string()))
+    }
+
+    pub fn vlc_settings(&self) -> VlcSettings {
+        VlcSettings {
+            binary: self.vlc.binary.clone(),
+            host: self.vlc.host.clone(),
+            port: self.vlc.port.to_string(),
+            password: self.vlc.password.clone().unwrap_or_else(random_password),
+            external: self.vlc.external,
+            extra_args: self.vlc.extra_args.clone(),
+            startup_timeout: Duration::from_secs(self.vlc.startup_timeout_secs),
+            play_timeout: Duration::from_secs(self.vlc.play_timeout_secs),
+        }
+    }
+
+    pub fn adhoc_policy(&self) -> AdhocPolicy {
+        AdhocPolicy {
+            enabled: self.playback.adhoc_links,
+            schemes: self.playback.allowed_schemes.clone(),
+            hosts: self.playback.allowed_hosts.clone(),
+        }
+    }
+
+    /// The configured listeners, or one on `server.bind` if there are none.
+    pub fn listeners(&self) -> Vec<Listener> {
+        if self.server.listeners.is_empty() {
+            return vec![Listener {
+                address: ListenAddress::Tcp(self.server.bind.parse().expect("validated")),
+                ui: true,
+                api: true,
+                tls: self.tls.enabled,
+            }];
+        }
+        self.server
+            .listeners
+            .iter()
+            .map(|listener| listener.resolve(self.tls.enabled).expect("validated"))
+            .collect()
+    }
+
+    pub fn tls_settings(&self) -> TlsSettings {
+        let dir = self.server.dir.join("tls");
+        TlsSettings {
+            cert: self
+                .tls
+                .cert
+                .clone()
+                .unwrap_or_else(|| dir.join("cert.pem")),
+            key: self.tls.key.clone().unwrap_or_else(|| dir.join("key.pem")),
+            self_signed: self.tls.self_signed,
+            hostnames: self.tls.hostnames.clone(),
+            reload_interval: Duration::from_secs(self.tls.reload_interval_secs),
+        }
+    }
+
+    pub fn watchdog_settings(&self) -> WatchdogSettings {
+        WatchdogSettings {
+            poll_interval: Duration::from_secs(self.watchdog.poll_interval_secs),
+            stall_polls: self.watchdog.stall_polls,
+            initial_backoff: Duration::from_secs(self.watchdog.initial_backoff_secs),
+            max_backoff: Duration::from_secs(self.watchdog.max_backoff_secs),
+        }
+    }
+
+    pub fn poller_settings(&self) -> PollerSettings {
+        PollerSettings {
+            fast_interval: Duration::from_millis(self.status.fast_interval_millis),
+            slow_interval: Duration::from_millis(self.status.slow_interval_millis),
+        }
+    }
+
+    pub fn supervisor_settings(&self) -> SupervisorSettings {
+        SupervisorSettings {
+            initial_backoff: Duration::from_secs(self.supervisor.initial_backoff_secs),
+            max_backoff: Duration::from_secs(self.supervisor.max_backoff_secs),
+            stable_after: Duration::from_secs(self.supervisor.stable_after_secs),
+            stop_timeout: Duration::from_secs(self.supervisor.stop_timeout_secs),
+            health_retries: self.vlc.health_retries,
+            health_interval_millis: self.vlc.health_interval_millis,
+        }
+    }
+}
+
+fn env_name(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.replace('.', "_").to_uppercase())
+}
+
+fn parse<T>(key: &str, value: &str) -> Result<T, HomeRadioError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value.parse().map_err(|e| {
+        HomeRadioError::InvalidConfig(format!("{}: invalid value '{}': {}", key, value, e))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{cli, test_support::TempDir};
+
+    /// the tests change the environment of the whole process
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn load(file: &str, vars: &[(&str, &str)], flags: &[&str]) -> Result<Config, HomeRadioError> {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = TempDir::new();
+        std::fs::create_dir_all(dir.path()).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(&path, file).unwrap();
+        let mut args = vec!["home-radio", "--config", path.to_str().unwrap(), "serve"];
+        args.extend_from_slice(flags);
+        let matches = cli::build_app().get_matches_from_safe(args).unwrap();
+        for (name, value) in vars {
+            env::set_var(name, value);
+        }
+        let config = Config::load(matches.subcommand_matches("serve").unwrap());
+        for (name, _) in vars {
+            env::remove_var(name);
+        }
+        config
+    }
+
+    fn problems(result: Result<Config, HomeRadioError>) -> String {
+        match result {
+            Err(HomeRadioError::InvalidConfig(message)) => message,
+            _ => panic!("expected an invalid configuration"),
+        }
+    }
+
+    #[test]
+    fn environment_overrides_the_file_and_flags_override_both() {
+        let file =
+            "[server]\nbind = \"127.0.0.1:9000\"\ndir = \"/from/file\"\n\n[vlc]\nport = 9001\n";
+        let vars = [
+            ("HOME_RADIO_SERVER_DIR", "/from/env"),
+            ("HOME_RADIO_VLC_PORT", "9002"),
+        ];
+        let config = load(file, &vars, &["--vlc-port", "9003"]).unwrap();
+
+        assert_eq!(config.server.bind, "127.0.0.1:9000");
+        assert_eq!(config.server.dir, PathBuf::from("/from/env"));
+        assert_eq!(config.vlc.port, 9003);
+    }
+
+    #[test]
+    fn environment_overrides_settings_without_a_flag() {
+        let file = "[watchdog]\nstall_polls = 5\n\n[vlc]\nextra_args = [\"--no-video\"]\n";
+        let vars = [
+            ("HOME_RADIO_WATCHDOG_STALL_POLLS", "7"),
+            ("HOME_RADIO_VLC_EXTRA_ARGS", "[\"--aout=alsa\"]"),
+            ("HOME_RADIO_LOGGING_ACCESS_LOG_FORMAT", "%r %s"),
+            (
+                "HOME_RADIO_SERVER_LISTENERS",
+                "[{ socket = \"/run/home-radio.sock\" }]",
+            ),
+        ];
+        let config = load(file, &vars, &[]).unwrap();
+
+        assert_eq!(config.watchdog.stall_polls, 7);
+        assert_eq!(config.vlc.extra_args, ["--aout=alsa"]);
+        assert_eq!(config.logging.access_log_format, "%r %s");
+        assert_eq!(
+            config.server.listeners[0].socket,
+            Some(PathBuf::from("/run/home-radio.sock"))
+        );
+    }
+
+    #[test]
+    fn unknown_environment_variables_are_rejected() {
+        let unknown_key = problems(load("", &[("HOME_RADIO_VLC_PORTS", "1")], &[]));
+        let unknown_section = problems(load("", &[("HOME_RADIO_RADIO_NAME", "x")], &[]));
+
+        assert!(unknown_key.starts_with("HOME_RADIO_VLC_PORTS: unknown field `ports`"));
+        assert_eq!(
+            unknown_section,
+            "HOME_RADIO_RADIO_NAME: not a setting of any configuration section"
+        );
+    }
+
+    #[test]
+    fn invalid_value_of_a_setting_without_a_flag_names_the_variable() {
+        let result = load(
+            "",
+            &[("HOME_RADIO_STATUS_FAST_INTERVAL_MILLIS", "soon")],
+            &[],
+        );
+
+        assert!(
+            problems(result).starts_with("HOME_RADIO_STATUS_FAST_INTERVAL_MILLIS: invalid type")
+        );
+    }
+
+    #[test]
+    fn switches_turn_settings_on_and_off() {
+        let file = "[server]\nautoplay = true\n";
+        let config = load(file, &[], &["--autoplay=false", "--watch-state"]).unwrap();
+
+        assert!(!config.server.autoplay);
+        assert!(config.storage.watch);
+    }
+
+    #[test]
+    fn switches_need_an_equals_sign_for_their_value() {
+        let args = ["home-radio", "serve", "--auth", "false"];
+
+        assert!(cli::build_app().get_matches_from_safe(args).is_err());
+    }
+
+    #[test]
+    fn validation_reports_all_problems() {
+        let file = "[server]\nbind = \"nowhere\"\n\n[vlc]\nport = 0\nexternal = true\n";
+
+        assert_eq!(
+            problems(load(file, &[], &[])),
+            "server.bind: 'nowhere' is not an address like 0.0.0.0:8080; \
+             vlc.port must not be 0; \
+             vlc.password is required when vlc.external is set"
+        );
+    }
+
+    #[test]
+    fn retry_settings_must_not_be_0() {
+        let file = "[vlc]\nhealth_retries = 0\n\n[watchdog]\nstall_polls = 0\ninitial_backoff_secs = 0\n\n\
+                    [supervisor]\ninitial_backoff_secs = 0\n";
+
+        assert_eq!(
+            problems(load(file, &[], &[])),
+            "vlc.health_retries must be greater than 0; \
+             watchdog.stall_polls must be greater than 0; \
+             watchdog.initial_backoff_secs must be greater than 0; \
+             supervisor.initial_backoff_secs must be greater than 0"
+        );
+    }
+
+    #[test]
+    fn invalid_environment_value_names_the_setting() {
+        let result = load("", &[("HOME_RADIO_VLC_PORT", "eighty")], &[]);
+
+        assert_eq!(
+            problems(result),
+            "vlc.port: invalid value 'eighty': invalid digit found in string"
+        );
+    }
+}
@@ -1,8 +1,13 @@
 use std::{io, num::ParseIntError};
 
-use awc;
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
 use awc::error::SendRequestError;
+use serde::Serialize;
 use thiserror::Error;
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::auth::Scope;
 
 #[derive(Error, Debug)]
 #[non_exhaustive]
@@ -13,17 +18,188 @@ pub enum HomeRadioError {
     Io(#[from] io::Error),
     #[error(transparent)]
     ParseInt(#[from] ParseIntError),
-
     #[error(transparent)]
-    SendRequestError(#[from] SendRequestError),
+    Sqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Join(#[from] tokio::task::JoinError),
+    #[error(transparent)]
+    Watch(#[from] notify::Error),
+
+    /// the request to the player failed, kept as text since the awc error can't be sent between threads
+    #[error("{0}")]
+    SendRequestError(String),
     #[error(transparent)]
-    UrlEncodedError(Box<dyn std::error::Error>),
+    UrlEncodedError(Box<dyn std::error::Error + Send + Sync>),
 
     #[error(transparent)]
     JsonPayloadError(#[from] awc::error::JsonPayloadError),
     #[error(transparent)]
     PayloadError(#[from] awc::error::PayloadError),
 
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    /// a request that can't be carried out as it is
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+
+    #[error("there is no media source with {0}")]
+    UnknownMediaSource(String),
+
+    /// a path below the api prefix that no route serves
+    #[error("there is no endpoint {0}")]
+    UnknownEndpoint(String),
+
+    #[error("authentication required")]
+    Unauthenticated,
+    #[error("invalid user name or password")]
+    InvalidCredentials,
+    #[error("this requires the {0} scope")]
+    Forbidden(Scope),
+
+    /// an ad-hoc link that isn't covered by the configured allow-list
+    #[error("playing {0} is not allowed")]
+    LinkNotAllowed(String),
+
+    #[error("tls: {0}")]
+    Tls(String),
+
+    #[error("{path} has format version {version}, which is newer than this release supports")]
+    UnsupportedStateVersion { path: String, version: u64 },
+
     #[error("internal vlc server is unhealthy")]
     VLCServerUnhealthy,
+
+    #[error("the player service is not running")]
+    PlayerServiceStopped,
+
+    #[error("playback was stopped or switched to another source in the meantime")]
+    PlaybackSuperseded,
+
+    #[error("none of the links of the media source could be played")]
+    NoPlayableSource,
+
+    #[error("could not resolve the host of {0}")]
+    StreamDnsError(String),
+    #[error("could not connect to {url}: {reason}")]
+    StreamUnreachable { url: String, reason: String },
+    #[error("{url} answered with http status {status}")]
+    StreamHttpStatus { url: String, status: u16 },
+    #[error("{url} serves unsupported content of type {content_type}")]
+    UnsupportedCodec { url: String, content_type: String },
+    #[error("{url} did not start playing within {seconds} seconds")]
+    StartupTimeout { url: String, seconds: u64 },
+    #[error("no link of the media source started playing within {seconds} seconds")]
+    PlayTimeout { seconds: u64 },
+}
+
+impl From<SendRequestError> for HomeRadioError {
+    fn from(e: SendRequestError) -> Self {
+        HomeRadioError::SendRequestError(e.to_string())
+    }
+}
+
+impl HomeRadioError {
+    /// Machine readable identifier of the error that stays stable across releases.
+    pub fn code(&self) -> &'static str {
+        match self {
+            HomeRadioError::InvalidConfig(_) => "invalid_config",
+            HomeRadioError::InvalidInput(_) => "invalid_input",
+            HomeRadioError::UnknownMediaSource(_) => "unknown_media_source",
+            HomeRadioError::UnknownEndpoint(_) => "unknown_endpoint",
+            HomeRadioError::Unauthenticated => "unauthenticated",
+            HomeRadioError::InvalidCredentials => "invalid_credentials",
+            HomeRadioError::Forbidden(_) => "forbidden",
+            HomeRadioError::LinkNotAllowed(_) => "link_not_allowed",
+            HomeRadioError::VLCServerUnhealthy => "player_unhealthy",
+            HomeRadioError::PlayerServiceStopped => "player_unavailable",
+            HomeRadioError::PlaybackSuperseded => "playback_superseded",
+            HomeRadioError::SendRequestError(_) => "player_unreachable",
+            HomeRadioError::JsonPayloadError(_) | HomeRadioError::PayloadError(_) => "player_error",
+            HomeRadioError::NoPlayableSource => "no_playable_source",
+            HomeRadioError::StreamDnsError(_) => "stream_dns_error",
+            HomeRadioError::StreamUnreachable { .. } => "stream_unreachable",
+            HomeRadioError::StreamHttpStatus { status: 404, .. }
+            | HomeRadioError::StreamHttpStatus { status: 410, .. } => "stream_not_found",
+            HomeRadioError::StreamHttpStatus { status: 401, .. }
+            | HomeRadioError::StreamHttpStatus { status: 403, .. } => "stream_forbidden",
+            HomeRadioError::StreamHttpStatus { .. } => "stream_http_error",
+            HomeRadioError::UnsupportedCodec { .. } => "unsupported_codec",
+            HomeRadioError::StartupTimeout { .. } => "startup_timeout",
+            HomeRadioError::PlayTimeout { .. } => "play_timeout",
+            _ => "internal_error",
+        }
+    }
+}
+
+/// Body of error responses, `code` is meant for programs and `message` for humans.
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// An error that is kept for the status, e.g. a failed write of the state or an exit of vlc.
+#[derive(Serialize, ToSchema, Clone)]
+pub struct ErrorReport {
+    /// seconds since the unix epoch
+    pub timestamp: u64,
+    /// the part of the server that failed, e.g. `storage` or `vlc`
+    pub component: String,
+    pub message: String,
+}
+
+impl From<&HomeRadioError> for ErrorBody {
+    fn from(e: &HomeRadioError) -> Self {
+        let message = match e.status_code() {
+            // details of internal errors are for the log, not for clients
+            StatusCode::INTERNAL_SERVER_ERROR => "internal server error".into(),
+            _ => e.to_string(),
+        };
+        ErrorBody {
+            code: e.code(),
+            message,
+        }
+    }
+}
+
+impl ResponseError for HomeRadioError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            HomeRadioError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            HomeRadioError::UnknownMediaSource(_) | HomeRadioError::UnknownEndpoint(_) => {
+                StatusCode::NOT_FOUND
+            }
+            HomeRadioError::Unauthenticated | HomeRadioError::InvalidCredentials => {
+                StatusCode::UNAUTHORIZED
+            }
+            HomeRadioError::LinkNotAllowed(_) | HomeRadioError::Forbidden(_) => {
+                StatusCode::FORBIDDEN
+            }
+            HomeRadioError::PlaybackSuperseded => StatusCode::CONFLICT,
+            HomeRadioError::VLCServerUnhealthy
+            | HomeRadioError::PlayerServiceStopped
+            | HomeRadioError::SendRequestError(_) => StatusCode::SERVICE_UNAVAILABLE,
+            HomeRadioError::JsonPayloadError(_)
+            | HomeRadioError::PayloadError(_)
+            | HomeRadioError::NoPlayableSource
+            | HomeRadioError::StreamDnsError(_)
+            | HomeRadioError::StreamUnreachable { .. }
+            | HomeRadioError::StreamHttpStatus { .. }
+            | HomeRadioError::UnsupportedCodec { .. }
+            | HomeRadioError::StartupTimeout { .. }
+            | HomeRadioError::PlayTimeout { .. } => StatusCode::BAD_GATEWAY,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        if status.is_server_error() {
+            error!("{}", self);
+        } else {
+            info!("rejected request: {}", self);
+        }
+        HttpResponse::build(status).json(ErrorBody::from(self))
+    }
 }
@@ -0,0 +1,132 @@
+use std::{convert::Infallible, time::Duration};
+
+use actix_web::web::Bytes;
+use futures_util::{stream, Stream};
+use serde::Serialize;
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    time::sleep,
+};
+use tracing::{debug, error};
+use utoipa::ToSchema;
+
+use crate::{
+    backend::PlaybackCandidate,
+    media_service::{Current, Metadata, PlaybackState, StatusHandle},
+};
+
+/// events a slow subscriber may fall behind before it misses some
+const CAPACITY: usize = 64;
+/// proxies close connections that stay silent for too long
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Something that changed and that clients may want to show without polling.
+#[derive(Serialize, ToSchema, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// playback was started, stopped or moved to another link
+    Playback {
+        current: Option<Current>,
+        active: Option<PlaybackCandidate>,
+    },
+    /// the state the player reports changed, `None` while it can't be reached
+    PlayerState {
+        state: Option<PlaybackState>,
+    },
+    NowPlaying {
+        metadata: Metadata,
+    },
+    Volume {
+        volume: u16,
+    },
+    /// media sources were added, changed or reloaded
+    Sources,
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::Playback { .. } => "playback",
+            Event::PlayerState { .. } => "player_state",
+            Event::NowPlaying { .. } => "now_playing",
+            Event::Volume { .. } => "volume",
+            Event::Sources => "sources",
+        }
+    }
+
+    /// The event in the server-sent events wire format.
+    fn to_sse(&self) -> Bytes {
+        match serde_json::to_string(self) {
+            Ok(data) => Bytes::from(format!("event: {}\ndata: {}\n\n", self.name(), data)),
+            Err(e) => {
+                error!("could not serialize event {:?}: {}", self, e);
+                Bytes::new()
+            }
+        }
+    }
+}
+
+/// Delivers events to everyone subscribed at the time they are published.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        EventBus { sender }
+    }
+}
+
+impl EventBus {
+    pub fn publish(&self, event: Event) {
+        // having no subscribers is fine
+        let _ = self.sender.send(event);
+    }
+
+    /// Body of a `text/event-stream` response that follows the bus until the client goes away.
+    pub fn sse_stream(&self) -> impl Stream<Item = Result<Bytes, Infallible>> {
+        stream::unfold(self.sender.subscribe(), |mut events| async move {
+            loop {
+                tokio::select! {
+                    event = events.recv() => match event {
+                        Ok(event) => return Some((Ok(event.to_sse()), events)),
+                        Err(RecvError::Lagged(missed)) => {
+                            debug!("event subscriber missed {} events", missed);
+                        }
+                        Err(RecvError::Closed) => return None,
+                    },
+                    _ = sleep(KEEPALIVE_INTERVAL) => {
+                        return Some((Ok(Bytes::from_static(b": keepalive\n\n")), events));
+                    }
+                }
+            }
+        })
+    }
+
+    /// Publishes changes of the player state and of the announced song.
+    pub async fn forward_player_status(self, status: StatusHandle) {
+        let mut snapshots = status.subscribe();
+        let mut last_state = None;
+        let mut last_metadata = None;
+        while snapshots.changed().await.is_ok() {
+            let (state, metadata) = match &*snapshots.borrow() {
+                Some(snapshot) => (Some(snapshot.state), Some(snapshot.metadata.clone())),
+                None => (None, None),
+            };
+            if state != last_state {
+                self.publish(Event::PlayerState { state });
+                last_state = state;
+            }
+            if let Some(metadata) = &metadata {
+                if last_metadata.as_ref() != Some(metadata) {
+                    self.publish(Event::NowPlaying {
+                        metadata: metadata.clone(),
+                    });
+                }
+            }
+            last_metadata = metadata;
+        }
+    }
+}
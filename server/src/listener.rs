@@ -0,0 +1,110 @@
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        net::UnixListener,
+    },
+    path::{Path, PathBuf},
+};
+
+/// Where a listener accepts connections.
+#[derive(Clone)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    /// a unix socket, `mode` are the permissions of the socket file
+    Unix {
+        path: PathBuf,
+        mode: u32,
+    },
+}
+
+/// A listener and what it serves. Each one gets its own http server.
+#[derive(Clone)]
+pub struct Listener {
+    pub address: ListenAddress,
+    pub ui: bool,
+    pub api: bool,
+    pub tls: bool,
+}
+
+impl Listener {
+    /// Binds the socket file, replacing a socket left behind by a previous run.
+    pub fn bind_unix(path: &PathBuf, mode: u32) -> io::Result<UnixListener> {
+        match std::fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        // bound in a directory only this user can enter and moved into place once it has its mode,
+        // so nobody can connect while the socket still has the permissions of the umask
+        let file_name = path.file_name().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a file", path.display()),
+            )
+        })?;
+        let private = path.with_file_name(format!(
+            ".{}.{}",
+            file_name.to_string_lossy(),
+            rand::random::<u32>()
+        ));
+        std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+        let bound = bind_private(&private.join(file_name), path, mode);
+        let _ = std::fs::remove_dir_all(&private);
+        bound
+    }
+}
+
+fn bind_private(private: &Path, path: &Path, mode: u32) -> io::Result<UnixListener> {
+    let listener = UnixListener::bind(private)?;
+    std::fs::set_permissions(private, std::fs::Permissions::from_mode(mode))?;
+    std::fs::rename(private, path)?;
+    Ok(listener)
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.address {
+            ListenAddress::Tcp(addr) if self.tls => write!(f, "https://{}", addr)?,
+            ListenAddress::Tcp(addr) => write!(f, "http://{}", addr)?,
+            ListenAddress::Unix { path, mode } => write!(f, "{} ({:o})", path.display(), mode)?,
+        }
+        let served = match (self.ui, self.api) {
+            (true, true) => "ui and api",
+            (true, false) => "ui",
+            _ => "api",
+        };
+        write!(f, " serving the {}", served)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn socket_is_created_with_its_mode() {
+        let dir = TempDir::new();
+        std::fs::create_dir_all(dir.path()).unwrap();
+        let path = dir.join("home-radio.sock");
+
+        let _listener = Listener::bind_unix(&path, 0o660).unwrap();
+
+        let meta = std::fs::symlink_metadata(&path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o660);
+        UnixStream::connect(&path).unwrap();
+        // nothing but the socket is left behind
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
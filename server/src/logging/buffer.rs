@@ -0,0 +1,198 @@
+use std::{
+    collections::VecDeque,
+    fmt::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Level, Subscriber,
+};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::media_service::unix_time;
+
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, PartialEq, PartialOrd, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl From<&Level> for LogLevel {
+    fn from(level: &Level) -> Self {
+        match *level {
+            Level::TRACE => LogLevel::Trace,
+            Level::DEBUG => LogLevel::Debug,
+            Level::INFO => LogLevel::Info,
+            Level::WARN => LogLevel::Warn,
+            Level::ERROR => LogLevel::Error,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema, Clone)]
+pub struct LogRecord {
+    pub timestamp: u64,
+    pub level: LogLevel,
+    pub target: String,
+    /// the spans it happened in, outermost first, e.g. `request{method=PUT path=/volume}`
+    pub spans: Vec<String>,
+    /// the message followed by the other fields of the event
+    pub message: String,
+}
+
+/// Which records `GET /logs` returns.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RecordQuery {
+    /// the least severe level to include
+    pub level: Option<LogLevel>,
+    /// unix time in seconds, inclusive
+    pub since: Option<u64>,
+    /// unix time in seconds, inclusive
+    pub until: Option<u64>,
+    /// only the newest ones
+    pub limit: Option<usize>,
+}
+
+/// Keeps the latest log records in memory, so they can be read without access to the host.
+#[derive(Clone)]
+pub struct LogBuffer {
+    capacity: usize,
+    records: Arc<Mutex<VecDeque<LogRecord>>>,
+}
+
+impl LogBuffer {
+    pub fn new(capacity: usize) -> Self {
+        LogBuffer {
+            capacity,
+            records: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    /// The matching records, oldest first.
+    pub fn records(&self, query: &RecordQuery) -> Vec<LogRecord> {
+        let records = self.records.lock().unwrap();
+        let mut matching: Vec<LogRecord> = records
+            .iter()
+            .rev()
+            .filter(|record| query.level.is_none_or(|level| record.level >= level))
+            .filter(|record| query.since.is_none_or(|since| record.timestamp >= since))
+            .filter(|record| query.until.is_none_or(|until| record.timestamp <= until))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect();
+        matching.reverse();
+        matching
+    }
+
+    fn push(&self, record: LogRecord) {
+        let mut records = self.records.lock().unwrap();
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+}
+
+/// The fields of a span as `name=value`, kept in the extensions of the span.
+struct SpanFields(String);
+
+impl<S> Layer<S> for LogBuffer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let mut fields = FieldWriter::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(fields.fields));
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(SpanFields(fields)) = extensions.get_mut::<SpanFields>() {
+            let mut writer = FieldWriter {
+                fields: std::mem::take(fields),
+                ..Default::default()
+            };
+            values.record(&mut writer);
+            *fields = writer.fields;
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        // records of the `log` crate carry their target and level in fields
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        let mut writer = FieldWriter::default();
+        event.record(&mut writer);
+        let mut message = writer.message;
+        if !writer.fields.is_empty() {
+            if !message.is_empty() {
+                message.push(' ');
+            }
+            message.push_str(&writer.fields);
+        }
+        let spans = ctx
+            .event_scope(event)
+            .map(|scope| {
+                scope
+                    .from_root()
+                    .map(|span| match span.extensions().get::<SpanFields>() {
+                        Some(SpanFields(fields)) if !fields.is_empty() => {
+                            format!("{}{{{}}}", span.name(), fields)
+                        }
+                        _ => span.name().to_string(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        self.push(LogRecord {
+            timestamp: unix_time(),
+            level: metadata.level().into(),
+            target: metadata.target().into(),
+            spans,
+            message,
+        });
+    }
+}
+
+#[derive(Default)]
+struct FieldWriter {
+    message: String,
+    fields: String,
+}
+
+impl Visit for FieldWriter {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{}", value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => {
+                let _ = write!(self.message, "{:?}", value);
+            }
+            name if name.starts_with("log.") => {}
+            name => {
+                if !self.fields.is_empty() {
+                    self.fields.push(' ');
+                }
+                let _ = write!(self.fields, "{}={:?}", name, value);
+            }
+        }
+    }
+}
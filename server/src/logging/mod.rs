@@ -0,0 +1,101 @@
+use std::{
+    io::{self, IsTerminal},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::{fmt, prelude::*, reload, util::SubscriberInitExt, EnvFilter, Registry};
+
+use crate::{config::LoggingConfig, errors::HomeRadioError};
+
+mod buffer;
+
+use buffer::LogBuffer;
+pub use buffer::{LogLevel, LogRecord, RecordQuery};
+
+/// How log lines are written to stderr.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// one json object per line, with the fields of the spans it happened in
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expected text or json".into()),
+        }
+    }
+}
+
+/// Changes the filter of the installed subscriber while the server runs and reads the latest records.
+#[derive(Clone)]
+pub struct LogControl {
+    handle: reload::Handle<EnvFilter, Registry>,
+    buffer: LogBuffer,
+}
+
+impl LogControl {
+    /// Empty if `logging.buffer_size` is 0.
+    pub fn records(&self, query: &RecordQuery) -> Vec<LogRecord> {
+        self.buffer.records(query)
+    }
+
+    pub fn filter(&self) -> String {
+        self.handle
+            .with_current(|filter| filter.to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn set_filter(&self, filter: &str) -> Result<(), HomeRadioError> {
+        let filter = parse_filter(filter).map_err(HomeRadioError::InvalidInput)?;
+        self.handle
+            .reload(filter)
+            .map_err(|e| HomeRadioError::InvalidInput(e.to_string()))
+    }
+}
+
+/// Checks a filter like `info,home_radio=debug`.
+pub fn parse_filter(filter: &str) -> Result<EnvFilter, String> {
+    EnvFilter::try_new(filter).map_err(|e| format!("invalid log filter '{}': {}", filter, e))
+}
+
+/// Installs the subscriber, which also receives the records of dependencies that use the `log` crate.
+pub fn init(config: &LoggingConfig) -> Result<LogControl, HomeRadioError> {
+    let filter = parse_filter(&config.level).map_err(HomeRadioError::InvalidConfig)?;
+    let (filter, handle) = reload::Layer::new(filter);
+    let (text, json) = match config.format {
+        LogFormat::Text => (
+            Some(
+                fmt::layer()
+                    .with_ansi(io::stderr().is_terminal())
+                    .with_writer(io::stderr),
+            ),
+            None,
+        ),
+        LogFormat::Json => (
+            None,
+            Some(
+                fmt::layer()
+                    .json()
+                    .with_span_list(true)
+                    .with_writer(io::stderr),
+            ),
+        ),
+    };
+    let buffer = LogBuffer::new(config.buffer_size);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(text)
+        .with(json)
+        .with((config.buffer_size > 0).then(|| buffer.clone()))
+        .try_init()
+        .map_err(|e| HomeRadioError::InvalidConfig(format!("could not set up logging: {}", e)))?;
+    Ok(LogControl { handle, buffer })
+}
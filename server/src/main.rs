@@ -1,22 +1,55 @@
-use std::{path::Path, sync::Mutex, process::Child};
+use std::{
+    io::{self, BufRead},
+    path::Path,
+    time::{Duration, Instant},
+};
 
 use actix_web::{
+    dev::{Server, ServerHandle, Service},
+    middleware::Logger,
     web::{self, Json},
     App, HttpResponse, HttpServer, Responder,
 };
-use backend::MediaSource;
-use env_logger;
+use api::Volume;
+use auth::{Auth, AuthStore, Scope};
+use backend::{MediaSource, MonitoredStorage, StateWatcher, Storage, StorageHandle, StorageKind};
+use clap::ArgMatches;
 use errors::HomeRadioError;
-use log::{error, info};
-use media_service::RemoteMediaService;
+use events::EventBus;
+use futures_util::future::{join, join_all, ready, try_join_all, Either};
+use listener::{ListenAddress, Listener};
+use logging::LogControl;
+use metrics::Metrics;
+use media_service::{
+    Current, PlaybackTarget, PlaybackWatchdog, PlayerHandle, PlayerService, ReconcileHandle,
+    Reconciler, RemoteMediaService, StatusHandle, StatusPoller, SupervisorHandle, VlcSupervisor,
+    WatchdogHandle,
+};
+use tls::CertStore;
+use tokio::{
+    select,
+    signal::unix::{signal, Signal, SignalKind},
+};
+use tracing::{error, info, info_span, Instrument};
 
 use crate::{
     backend::FileBackend,
+    config::Config,
 };
+mod api;
+mod auth;
 mod backend;
 mod cli;
+mod config;
 mod errors;
+mod events;
+mod listener;
+mod logging;
 mod media_service;
+mod metrics;
+#[cfg(test)]
+mod test_support;
+mod tls;
 
 const INDEX_HTML: &str = include_str!("./ui/index.html");
 const FORM_HTML: &str = include_str!("./ui/add-media-form.html");
@@ -24,147 +57,524 @@ const INDEX_JS: &str = include_str!("./ui/index.js");
 const INDEX_CSS: &str = include_str!("./ui/index.css");
 const COMMON_JS: &str = include_str!("./ui/common.js");
 const FORM_JS: &str = include_str!("./ui/form.js");
+const LOGIN_HTML: &str = include_str!("./ui/login.html");
+const LOGIN_JS: &str = include_str!("./ui/login.js");
+const DIAGNOSTICS_HTML: &str = include_str!("./ui/diagnostics.html");
+const DIAGNOSTICS_JS: &str = include_str!("./ui/diagnostics.js");
 const FAVICON: &[u8] = include_bytes!("./ui/favicon.ico");
 const ANDROID_FAVICON: &[u8] = include_bytes!("./ui/android-chrome-192x192.png");
 
 #[actix_web::main]
-async fn main() -> Result<(), HomeRadioError> {
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run() -> Result<(), HomeRadioError> {
     let app = cli::build_app();
     let matches = app.get_matches();
 
     match matches.subcommand() {
         ("serve", Some(args)) => {
-            let dir = args.value_of("dir").unwrap();
-            let autoplay = args.is_present("autoplay");
-            serve(dir, autoplay).await?;
+            let config = Config::load(args)?;
+            serve(config).await?;
+        }
+        ("storage", Some(args)) => match args.subcommand() {
+            ("import", Some(args)) => {
+                let config = Config::load(args)?;
+                import_files(&config, Path::new(args.value_of("from").unwrap())).await?;
+            }
+            _ => unreachable!(),
+        },
+        ("user", Some(args)) => {
+            let (command, args) = args.subcommand();
+            let args = args.unwrap();
+            let config = Config::load(args)?;
+            manage_users(&config, command, args).await?;
+        }
+        ("token", Some(args)) => {
+            let (command, args) = args.subcommand();
+            let args = args.unwrap();
+            let config = Config::load(args)?;
+            manage_tokens(&config, command, args).await?;
+        }
+        ("config", Some(args)) => match args.subcommand() {
+            ("show", Some(args)) => {
+                let config = Config::load(args)?;
+                print!("{}", config.to_toml()?);
+            }
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/// One-shot migration of a state dir of the file storage into the configured storage.
+async fn import_files(config: &Config, from: &Path) -> Result<(), HomeRadioError> {
+    if !from.join("media-sources.json").is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} contains no media-sources.json", from.display()),
+        )
+        .into());
+    }
+    if config.storage.backend == StorageKind::File && from == config.server.dir {
+        return Err(HomeRadioError::InvalidConfig(
+            "can't import the file storage into itself, choose another storage".into(),
+        ));
+    }
+    let source = FileBackend::new(from).await?;
+    let target = backend::open(config.storage.backend, &config.server.dir).await?;
+    backend::import(&source, target.as_ref()).await?;
+    println!(
+        "imported {} media sources from {}",
+        source.get_media_sources().await?.len(),
+        from.display()
+    );
+    Ok(())
+}
+
+async fn manage_users(
+    config: &Config,
+    command: &str,
+    args: &ArgMatches<'_>,
+) -> Result<(), HomeRadioError> {
+    let path = AuthStore::path(&config.server.dir);
+    let mut store = AuthStore::load(&path)?;
+    match command {
+        "add" => {
+            let name = args.value_of("name").unwrap();
+            let scope: Scope = args.value_of("scope").unwrap().parse().unwrap();
+            let password = if args.is_present("password-stdin") {
+                let mut line = String::new();
+                io::stdin().lock().read_line(&mut line)?;
+                line.trim_end_matches(&['\r', '\n'][..]).to_string()
+            } else {
+                let password = rpassword::prompt_password("password: ")?;
+                if password != rpassword::prompt_password("repeat password: ")? {
+                    return Err(HomeRadioError::InvalidInput(
+                        "the passwords don't match".into(),
+                    ));
+                }
+                password
+            };
+            store.set_user(name, &password, scope)?;
+            store.save(&path).await?;
+            println!("saved user {} with scope {}", name, scope);
+        }
+        "remove" => {
+            let name = args.value_of("name").unwrap();
+            if !store.remove_user(name) {
+                return Err(HomeRadioError::InvalidInput(format!(
+                    "there is no user {}",
+                    name
+                )));
+            }
+            store.save(&path).await?;
+            println!("removed user {}", name);
+        }
+        "list" => {
+            for user in &store.users {
+                println!("{}\t{}", user.name, user.scope);
+            }
         }
         _ => unreachable!(),
     }
     Ok(())
 }
 
-struct ProcessCleaner {
-    inner: Child
+async fn manage_tokens(
+    config: &Config,
+    command: &str,
+    args: &ArgMatches<'_>,
+) -> Result<(), HomeRadioError> {
+    let path = AuthStore::path(&config.server.dir);
+    let mut store = AuthStore::load(&path)?;
+    match command {
+        "create" => {
+            let scope: Scope = args.value_of("scope").unwrap().parse().unwrap();
+            let token = store.create_token(args.value_of("name").unwrap(), scope);
+            store.save(&path).await?;
+            eprintln!("store this token now, it can't be shown again:");
+            println!("{}", token);
+        }
+        "revoke" => {
+            let id = args.value_of("id").unwrap();
+            if !store.revoke_token(id) {
+                return Err(HomeRadioError::InvalidInput(format!(
+                    "there is no token with id {}",
+                    id
+                )));
+            }
+            store.save(&path).await?;
+            println!("revoked token {}", id);
+        }
+        "list" => {
+            for token in &store.tokens {
+                println!(
+                    "{}\t{}\t{}\tcreated at {}",
+                    token.id, token.scope, token.name, token.created_at
+                );
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
 }
 
-impl Drop for ProcessCleaner {
-    fn drop(&mut self) {
-        let _err = self.inner.kill();
+async fn serve(config: Config) -> Result<(), HomeRadioError> {
+    let logs = logging::init(&config.logging)?;
+    // right away, so a signal during the start leads to a clean stop once everything is up
+    let mut signals = ShutdownSignals::new()?;
+    let fb = MonitoredStorage::new(
+        backend::open(config.storage.backend, &config.server.dir).await?,
+        config.storage.backend,
+        &config.server.dir,
+    );
+    let storage = fb.handle();
+    let vlc_settings = config.vlc_settings();
+
+    // a spawned vlc process gets killed when its supervisor is dropped
+    let supervisor = if vlc_settings.external {
+        info!(
+            "using external vlc at {}:{}",
+            &vlc_settings.host, &vlc_settings.port
+        );
+        None
+    } else {
+        let vlc_config = vlc_settings.write_config(&config.server.dir).await?;
+        Some(VlcSupervisor::spawn(
+            vlc_settings.binary.clone(),
+            vlc_settings.args(&vlc_config),
+            config.supervisor_settings(),
+        )?)
+    };
+    let vlc = supervisor
+        .as_ref()
+        .map(VlcSupervisor::handle)
+        .unwrap_or_default();
+    let metrics = Metrics::default();
+    let srvc = vlc_settings.connect().with_metrics(metrics.clone());
+    actix_web::rt::spawn(StatusPoller::new(config.poller_settings(), srvc.clone()).run());
+    let events = EventBus::default();
+    actix_web::rt::spawn(events.clone().forward_player_status(srvc.status()));
+    let watchdog = WatchdogHandle::default();
+    // the player service owns the awc client of `srvc`, which is bound to this thread
+    let (player_service, player) = PlayerService::new(
+        Box::new(fb),
+        srvc.clone(),
+        watchdog.clone(),
+        events.clone(),
+        config.adhoc_policy(),
+    )
+    .await?;
+    actix_web::rt::spawn(player_service.run());
+    if config.storage.watch {
+        let mut watcher = StateWatcher::new(&config.server.dir, storage.own_writes())?;
+        let player = player.clone();
+        actix_web::rt::spawn(async move {
+            while let Some(changed) = watcher.changed().await {
+                if let Err(e) = player.reload().await {
+                    let changed: Vec<_> =
+                        changed.iter().map(|path| path.display().to_string()).collect();
+                    error!(
+                        "could not reload the state after {} changed, keeping the state from \
+                         before: {}. On the next start a file that can't be read is moved to \
+                         <file>.corrupt and replaced by its backup",
+                        changed.join(", "),
+                        e
+                    );
+                }
+            }
+        });
+    }
+
+    if config.server.autoplay {
+        // trying the links can take a while, serve in the meantime
+        let (srvc, player) = (srvc.clone(), player.clone());
+        let (retries, interval) = (config.vlc.health_retries, config.vlc.health_interval_millis);
+        actix_web::rt::spawn(async move {
+            if let Err(e) = autoplay(&srvc, &player, retries, interval).await {
+                error!("autoplay failed: {}", e);
+            }
+        });
     }
+
+    let supervisor = supervisor
+        .map(|supervisor| actix_web::rt::spawn(supervisor.run(srvc.clone(), player.clone())));
+    actix_web::rt::spawn(
+        PlaybackWatchdog::new(
+            config.watchdog_settings(),
+            watchdog.clone(),
+            srvc.clone(),
+            player.clone(),
+        )
+        .run(),
+    );
+    let reconciler = ReconcileHandle::default();
+    actix_web::rt::spawn(
+        Reconciler::new(
+            Duration::from_secs(config.reconcile.interval_secs),
+            srvc.clone(),
+            player.clone(),
+            reconciler.clone(),
+        )
+        .run(),
+    );
+    let auth = if config.auth.enabled {
+        Auth::new(
+            &config.server.dir,
+            Duration::from_secs(config.auth.session_ttl_hours * 3600),
+        )?
+    } else {
+        Auth::default()
+    };
+    actix_web::rt::spawn(auth.clone().watch_store());
+    let state = AppState {
+        player: web::Data::new(player.clone()),
+        watchdog: web::Data::new(watchdog),
+        vlc: web::Data::new(vlc.clone()),
+        reconciler: web::Data::new(reconciler),
+        status: web::Data::new(srvc.status()),
+        events: web::Data::new(events),
+        storage: web::Data::new(storage),
+        metrics: web::Data::new(metrics),
+        logs: web::Data::new(logs),
+        auth,
+    };
+
+    let result = run_servers(&config, state, &mut signals).await;
+
+    let fade_out = Duration::from_secs(config.shutdown.fade_out_secs);
+    if let Err(e) = player.shutdown(fade_out).await {
+        error!("could not save the state: {}", e);
+    }
+    if let Some(supervisor) = supervisor {
+        vlc.stop();
+        let _ = supervisor.await;
+    }
+    info!("stopped");
+    result
 }
 
-async fn serve<A: AsRef<Path>>(dir: A, autoplay: bool) -> Result<(), HomeRadioError> {
-    std::env::set_var("RUST_LOG", "debug");
-    env_logger::init();
-    let fb = FileBackend::new(dir.as_ref()).await?;
-
-    let vlc_process = std::process::Command::new("/usr/bin/vlc")
-        .arg("-I")
-        .arg("http")
-        .arg("--no-video")
-        .arg("--vout")
-        .arg("none")
-        .arg("--http-port")
-        .arg("8090")
-        .arg("--http-password")
-        .arg("foo")
-        .spawn()?;
-    
-    // kill the vlc process when this goes out of scope
-    let _cleaner = ProcessCleaner{inner: vlc_process};
-    let srvc = RemoteMediaService::new_with_auth("localhost".into(), "8090".into(), "foo".into());
-    if autoplay {
-        let current_src = fb.get_current_media_source().await?;
-        srvc.wait_for_healthy(20, 200).await?;
-        if let Some(current) = current_src {
-            let vol = fb.get_volume().await?;
-
-            srvc.play(&current, vol).await?;
-        } else {
-            let sources = fb.get_media_sources().await?;
-            let default_source = sources.iter().find(|src| src.default_source);
-            if let Some(src) = default_source {
-                let vol = fb.get_volume().await?;
-                srvc.play(&src.link, vol).await?;
+/// Serves on all listeners until one of them fails or a signal asks to stop.
+async fn run_servers(
+    config: &Config,
+    state: AppState,
+    signals: &mut ShutdownSignals,
+) -> Result<(), HomeRadioError> {
+    let listeners = config.listeners();
+    let tls = if listeners.iter().any(|listener| listener.tls) {
+        let certs = CertStore::new(config.tls_settings()).await?;
+        actix_web::rt::spawn(certs.clone().run());
+        Some(certs)
+    } else {
+        None
+    };
+
+    let mut servers = Vec::new();
+    for listener in listeners {
+        let state = state.clone();
+        let (ui, api) = (listener.ui, listener.api);
+        let access_log = config.logging.access_log;
+        let access_log_format = config.logging.access_log_format.clone();
+        let server = HttpServer::new(move || {
+            let check = state.auth.clone();
+            let metrics = state.metrics.clone();
+            App::new()
+                .wrap_fn(move |req, srv| match check.check(&req) {
+                    Ok(()) => Either::Left(srv.call(req)),
+                    Err(e) => Either::Right(ready(Err(e.into()))),
+                })
+                // outside of the authentication, so rejected requests are counted and logged too
+                .wrap_fn(move |req, srv| {
+                    let metrics = metrics.clone();
+                    let route = req.match_pattern().unwrap_or_else(|| "unmatched".into());
+                    let method = req.method().to_string();
+                    let started = Instant::now();
+                    let span = info_span!("request", method = %method, path = %req.path());
+                    let response = span.in_scope(|| srv.call(req));
+                    async move {
+                        let response = response.await;
+                        let status = match &response {
+                            Ok(response) => response.status(),
+                            Err(e) => e.as_response_error().status_code(),
+                        };
+                        metrics.observe_request(&route, &method, status.as_u16(), started.elapsed());
+                        response
+                    }
+                    .instrument(span)
+                })
+                .wrap(access_logger(access_log, &access_log_format))
+                .configure(|cfg| state.register(cfg))
+                .configure(|cfg| routes(cfg, ui, api))
+        })
+        // the servers of all listeners are stopped together, see below
+        .disable_signals()
+        .shutdown_timeout(config.shutdown.timeout_secs);
+        let server = match (&listener.address, &tls) {
+            (ListenAddress::Tcp(addr), Some(certs)) if listener.tls => {
+                server.bind_rustls(addr, certs.server_config())?
+            }
+            (ListenAddress::Tcp(addr), _) => server.bind(addr)?,
+            (ListenAddress::Unix { path, mode }, _) => {
+                server.listen_uds(Listener::bind_unix(path, *mode)?)?
             }
+        };
+        info!("listening on {}", listener);
+        servers.push(server.run());
+    }
+    let handles: Vec<ServerHandle> = servers.iter().map(Server::handle).collect();
+    let mut running = try_join_all(servers);
+    select! {
+        // the servers only stop on their own if they fail
+        result = &mut running => return result.map(|_| ()).map_err(HomeRadioError::from),
+        signal = signals.recv() => info!("{} received, shutting down", signal),
+    }
+    // the servers handle the stop while they are polled
+    let stopping = join_all(handles.iter().map(|handle| handle.stop(true)));
+    join(stopping, running).await.1?;
+    Ok(())
+}
+
+/// Plays the current source, or the default one if there is none. If it can't be played,
+/// it stays the current source and the watchdog retries it.
+async fn autoplay(
+    srvc: &RemoteMediaService,
+    player: &PlayerHandle,
+    health_retries: u16,
+    health_interval_millis: u16,
+) -> Result<(), HomeRadioError> {
+    let id = match player.current_media_source().await? {
+        Some(Current::Source(id)) => Some(id),
+        _ => player
+            .media_sources()
+            .await?
+            .into_iter()
+            .find(|src| src.default_source)
+            .map(|src| src.id),
+    };
+    let id = match id {
+        Some(id) => id,
+        None => return Ok(()),
+    };
+    if let Err(e) = srvc
+        .wait_for_healthy(health_retries, health_interval_millis)
+        .await
+    {
+        error!("vlc is not healthy yet: {}", e);
+    }
+    player.autoplay(id).await?;
+    Ok(())
+}
+
+/// SIGTERM and SIGINT, which stop the server gracefully instead of killing it once they are registered.
+struct ShutdownSignals {
+    terminate: Signal,
+    interrupt: Signal,
+}
+
+impl ShutdownSignals {
+    fn new() -> io::Result<Self> {
+        Ok(ShutdownSignals {
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?,
+        })
+    }
+
+    /// Resolves with the name of the first signal that arrives, including ones that arrived before.
+    async fn recv(&mut self) -> &'static str {
+        select! {
+            _ = self.terminate.recv() => "SIGTERM",
+            _ = self.interrupt.recv() => "SIGINT",
         }
     }
+}
+
+/// The access log of actix-web. Its response type differs from the service it wraps, so instead of being
+/// left out when disabled it excludes every path.
+fn access_logger(enabled: bool, format: &str) -> Logger {
+    let logger = Logger::new(format);
+    if enabled {
+        logger
+    } else {
+        logger.exclude_regex("")
+    }
+}
 
-    let backend = web::Data::new(Mutex::new(fb));
+/// What the handlers get from the app, shared by the servers of all listeners.
+#[derive(Clone)]
+struct AppState {
+    player: web::Data<PlayerHandle>,
+    watchdog: web::Data<WatchdogHandle>,
+    vlc: web::Data<SupervisorHandle>,
+    reconciler: web::Data<ReconcileHandle>,
+    status: web::Data<StatusHandle>,
+    events: web::Data<EventBus>,
+    storage: web::Data<StorageHandle>,
+    metrics: web::Data<Metrics>,
+    logs: web::Data<LogControl>,
+    auth: Auth,
+}
 
-    HttpServer::new(move || {
-        let srvc =
-            RemoteMediaService::new_with_auth("localhost".into(), "8090".into(), "foo".into());
+impl AppState {
+    fn register(&self, cfg: &mut web::ServiceConfig) {
+        cfg.app_data(web::JsonConfig::default().error_handler(|e, _| {
+            HomeRadioError::InvalidInput(e.to_string()).into()
+        }))
+        .app_data(web::QueryConfig::default().error_handler(|e, _| {
+            HomeRadioError::InvalidInput(e.to_string()).into()
+        }))
+        .app_data(self.player.clone())
+        .app_data(self.watchdog.clone())
+        .app_data(self.vlc.clone())
+        .app_data(self.reconciler.clone())
+        .app_data(self.status.clone())
+        .app_data(self.events.clone())
+        .app_data(self.storage.clone())
+        .app_data(self.metrics.clone())
+        .app_data(self.logs.clone())
+        .app_data(web::Data::new(self.auth.clone()));
+    }
+}
 
-        App::new()
-            //.wrap(Logger::new("%a %{User-Agent}i"))
-            .app_data(web::Data::new(srvc))
-            .app_data(backend.clone())
-            // ui routes
-            .route("/", web::get().to(index_html))
+/// Registers what a listener serves.
+fn routes(cfg: &mut web::ServiceConfig, ui: bool, api: bool) {
+    // every listener answers the health checks and serves the metrics
+    cfg.route("/healthz", web::get().to(api::healthz))
+        .route("/readyz", web::get().to(api::readyz))
+        .route("/metrics", web::get().to(api::get_metrics));
+    if ui {
+        cfg.route("/", web::get().to(index_html))
             .route("index.css", web::get().to(index_css))
             .route("index.html", web::get().to(index_html))
             .route("index.js", web::get().to(index_js))
             .route("common.js", web::get().to(common_js))
             .route("form.js", web::get().to(form_js))
             .route("add-media-form.html", web::get().to(media_form_html))
+            .route("login.html", web::get().to(login_html))
+            .route("login.js", web::get().to(login_js))
+            .route("diagnostics.html", web::get().to(diagnostics_html))
+            .route("diagnostics.js", web::get().to(diagnostics_js))
             .route("favicon.ico", web::get().to(favicon))
-            .route("android-chrome-192x192.png", web::get().to(android_favicon))
-            // resource routes
-            .route("/media", web::get().to(get_media_sources))
+            .route("android-chrome-192x192.png", web::get().to(android_favicon));
+    }
+    if api {
+        cfg.service(api::scope())
+            // legacy routes
+            .route("/media", web::get().to(api::list_sources))
             .route("/media", web::put().to(add_media_source))
-            // media control routes
             .route("/start", web::post().to(start_playback))
             .route("/stop", web::post().to(stop_playback))
             .route("/volume", web::get().to(get_current_volume))
             .route("/volume", web::put().to(set_current_volume))
-    })
-    .bind("0.0.0.0:8080")?
-    .run()
-    .await?;
-    Ok(())
-}
-
-async fn add_media_source(
-    body: Json<MediaSource>,
-    backend: web::Data<Mutex<FileBackend>>,
-) -> impl Responder {
-    let result = { backend.lock().unwrap().add_media_source(body.0).await };
-    if let Err(e) = result {
-        error!("{}", e);
-        return HttpResponse::InternalServerError().into();
-    }
-    HttpResponse::Ok()
-}
-
-async fn get_media_sources(backend: web::Data<Mutex<FileBackend>>) -> impl Responder {
-    let result = {
-        let backend = backend.lock().unwrap();
-        (
-            backend.get_media_sources().await,
-            backend.get_current_media_source().await,
-        )
-    };
-
-    let (mut media_sources, current_source) = match result {
-        (_, Err(e)) | (Err(e), _) => {
-            error!("{}", e);
-            return HttpResponse::InternalServerError().into();
-        }
-        (Ok(media_sources), Ok(current_source)) => (media_sources, current_source),
-    };
-
-    if let Some(current_source) = current_source {
-        for src in media_sources.iter_mut() {
-            if current_source == src.link {
-                src.currently_playing = Some(true)
-            } else {
-                src.currently_playing = None;
-            }
-        }
+            .route("/events", web::get().to(api::get_events))
+            .route("/status", web::get().to(api::get_status))
+            .route("/logs", web::get().to(api::get_logs));
     }
-
-    HttpResponse::Ok().json(media_sources)
 }
 
 async fn index_css() -> impl Responder {
@@ -198,98 +608,173 @@ async fn form_js() -> impl Responder {
         .body(FORM_JS)
 }
 
+async fn login_html() -> impl Responder {
+    HttpResponse::Ok().content_type("text/html").body(LOGIN_HTML)
+}
+
+async fn login_js() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("application/javascript")
+        .body(LOGIN_JS)
+}
+
+async fn diagnostics_html() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html")
+        .body(DIAGNOSTICS_HTML)
+}
+
+async fn diagnostics_js() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("application/javascript")
+        .body(DIAGNOSTICS_JS)
+}
+
 async fn index_js() -> impl Responder {
     HttpResponse::Ok()
         .content_type("application/javascript")
         .body(INDEX_JS)
 }
 
-async fn set_current_volume(
-    backend: web::Data<Mutex<FileBackend>>,
-    srvc: web::Data<RemoteMediaService>,
+// legacy routes, kept for clients from before /api/v1
+
+async fn add_media_source(
+    player: web::Data<PlayerHandle>,
+    body: Json<MediaSource>,
+) -> Result<HttpResponse, HomeRadioError> {
+    api::add_source(player, body).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Takes the link as plain text.
+async fn start_playback(
+    player: web::Data<PlayerHandle>,
+    watchdog: web::Data<WatchdogHandle>,
     body: String,
-) -> impl Responder {
-    let result = body.parse::<u16>();
-    let amount = if let Ok(amount) = result {
-        amount
-    } else {
-        return HttpResponse::BadRequest().body("invalid volume");
-    };
+) -> Result<HttpResponse, HomeRadioError> {
+    let target = PlaybackTarget::Link(body.trim().into());
+    api::start_playback(player, watchdog, Json(target)).await?;
+    Ok(HttpResponse::Ok().finish())
+}
 
-    let result = { backend.lock().unwrap().set_volume(amount).await };
-    info!("default volume set to {}", amount);
-    if let Err(e) = result {
-        error!("{}", e);
-        return HttpResponse::InternalServerError().into();
-    }
-    let result = srvc.set_volume(amount).await;
+async fn stop_playback(
+    player: web::Data<PlayerHandle>,
+    watchdog: web::Data<WatchdogHandle>,
+) -> Result<HttpResponse, HomeRadioError> {
+    api::stop_playback(player, watchdog).await?;
+    Ok(HttpResponse::Ok().finish())
+}
 
-    if let Err(e) = result {
-        error!("{}", e);
-        HttpResponse::InternalServerError().into()
-    } else {
-        HttpResponse::Ok().into()
-    }
+async fn get_current_volume(
+    player: web::Data<PlayerHandle>,
+) -> Result<HttpResponse, HomeRadioError> {
+    let vol = api::get_volume(player).await?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain")
+        .body(vol.volume.to_string()))
 }
 
-async fn start_playback(
-    backend: web::Data<Mutex<FileBackend>>,
-    srvc: web::Data<RemoteMediaService>,
+async fn set_current_volume(
+    player: web::Data<PlayerHandle>,
     body: String,
-) -> impl Responder {
-    info!("starting playback of {}", &body);
-    let vol = { backend.lock().unwrap().get_volume().await };
-    let vol = if let Err(e) = vol {
-        error!("error getting current volume: {}", e);
-        return HttpResponse::InternalServerError().into();
-    } else {
-        vol.unwrap()
-    };
+) -> Result<HttpResponse, HomeRadioError> {
+    let volume = body
+        .trim()
+        .parse::<u16>()
+        .map_err(|_| HomeRadioError::InvalidInput(format!("invalid volume '{}'", body)))?;
+    api::set_volume(player, Json(Volume { volume })).await?;
+    Ok(HttpResponse::Ok().finish())
+}
 
-    let result = srvc.play(&body, vol).await;
-    if let Err(e) = result {
-        error!("error starting playback of url {}: {}", &body, e);
-        HttpResponse::InternalServerError().body(e.to_string())
-    } else {
-        let result = {
-            backend
-                .lock()
-                .unwrap()
-                .set_current_media_source(&body)
-                .await
-        };
-        if let Err(e) = result {
-            error!("{}", e);
-        }
-        HttpResponse::Ok().into()
+#[cfg(test)]
+mod tests {
+    use backend::{SqliteBackend, SQLITE_FILE};
+
+    use super::*;
+    use crate::test_support::{source, TempDir};
+
+    fn sqlite_config(dir: &Path) -> Config {
+        let mut config = Config::default();
+        config.storage.backend = StorageKind::Sqlite;
+        config.server.dir = dir.to_path_buf();
+        config
     }
-}
 
-async fn stop_playback(
-    srvc: web::Data<RemoteMediaService>,
-    backend: web::Data<Mutex<FileBackend>>,
-) -> impl Responder {
-    let result = { backend.lock().unwrap().remove_current_media_source().await };
-    if let Err(e) = result {
-        error!("error removing current playback source: {}", e);
-        return HttpResponse::InternalServerError();
+    #[tokio::test]
+    async fn import_files_copies_the_state_into_the_configured_storage() {
+        let (from, to) = (TempDir::new(), TempDir::new());
+        let files = FileBackend::new(from.path()).await.unwrap();
+        files
+            .add_media_source(source("One", "http://one.example"))
+            .await
+            .unwrap();
+        files
+            .add_media_source(source("Two", "http://two.example"))
+            .await
+            .unwrap();
+        files.set_volume(70).await.unwrap();
+        files.set_current_media_source(2).await.unwrap();
+        // sources with a name that already exists are replaced, their id is kept
+        let existing = SqliteBackend::new(to.join(SQLITE_FILE)).await.unwrap();
+        existing
+            .add_media_source(source("Zero", "http://zero.example"))
+            .await
+            .unwrap();
+        existing
+            .add_media_source(source("Two", "http://old.example"))
+            .await
+            .unwrap();
+        drop(existing);
+
+        import_files(&sqlite_config(to.path()), from.path())
+            .await
+            .unwrap();
+
+        let target = SqliteBackend::new(to.join(SQLITE_FILE)).await.unwrap();
+        let sources = target.get_media_sources().await.unwrap();
+        let sources: Vec<(u64, &str, &str)> = sources
+            .iter()
+            .map(|src| (src.id, &src.name[..], &src.link[..]))
+            .collect();
+        assert_eq!(
+            sources,
+            [
+                (1, "Zero", "http://zero.example"),
+                (2, "Two", "http://two.example"),
+                (3, "One", "http://one.example")
+            ]
+        );
+        assert_eq!(target.get_volume().await.unwrap(), 70);
+        assert_eq!(target.get_current_media_source().await.unwrap(), Some(2));
     }
 
-    let result = srvc.stop().await;
-    if let Err(e) = result {
-        error!("error stopping playback: {}", e);
-        HttpResponse::InternalServerError()
-    } else {
-        HttpResponse::Ok()
+    #[tokio::test]
+    async fn import_files_needs_media_sources() {
+        let (from, to) = (TempDir::new(), TempDir::new());
+        std::fs::create_dir_all(from.path()).unwrap();
+
+        let result = import_files(&sqlite_config(to.path()), from.path()).await;
+
+        assert!(
+            matches!(result, Err(HomeRadioError::Io(e)) if e.kind() == io::ErrorKind::NotFound)
+        );
+        assert!(!to.path().exists());
     }
-}
 
-async fn get_current_volume(backend: web::Data<Mutex<FileBackend>>) -> impl Responder {
-    let result = { backend.lock().unwrap().get_volume().await };
-    match result {
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
-        Ok(vol) => HttpResponse::Ok()
-            .content_type("text/plain")
-            .body(vol.to_string()),
+    #[tokio::test]
+    async fn import_files_refuses_to_import_into_itself() {
+        let dir = TempDir::new();
+        FileBackend::new(dir.path())
+            .await
+            .unwrap()
+            .add_media_source(source("One", "http://one.example"))
+            .await
+            .unwrap();
+        let mut config = sqlite_config(dir.path());
+        config.storage.backend = StorageKind::File;
+
+        let result = import_files(&config, dir.path()).await;
+
+        assert!(matches!(result, Err(HomeRadioError::InvalidConfig(_))));
     }
-}
\ No newline at end of file
+}
@@ -1,2 +1,24 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod player;
+mod reconciler;
 mod remote_media_service;
+mod settings;
+mod status;
+mod supervisor;
+mod watchdog;
+pub use player::*;
+pub use reconciler::*;
 pub use remote_media_service::*;
+pub use settings::*;
+pub use status::*;
+pub use supervisor::*;
+pub use watchdog::*;
+
+/// seconds since the unix epoch, used for timestamps in reports
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,972 @@
+use std::{
+    fmt, future,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::{JoinError, JoinHandle},
+};
+use tracing::{error, info, warn, Instrument, Span};
+use utoipa::ToSchema;
+
+use crate::{
+    backend::{playback_candidates, MediaSource, PlaybackCandidate, Storage},
+    errors::HomeRadioError,
+    events::{Event, EventBus},
+};
+
+use super::{AdhocPolicy, Mismatch, MismatchKind, Observation, RemoteMediaService, WatchdogHandle};
+
+/// number of commands that may wait for the player service before senders have to wait too
+const QUEUE_SIZE: usize = 32;
+
+type Reply<T> = oneshot::Sender<Result<T, HomeRadioError>>;
+/// a command and the span of the requester, so the log lines of handling it show up there
+type Queued = (Command, Span);
+
+/// Requests to the `PlayerService`, each one carries the channel its answer is sent on.
+enum Command {
+    GetMediaSources(Reply<Vec<MediaSource>>),
+    AddMediaSource(MediaSource, Reply<MediaSource>),
+    GetVolume(Reply<u16>),
+    SetVolume(u16, Reply<()>),
+    GetCurrentMediaSource(Reply<Option<Current>>),
+    Start(PlaybackTarget, Reply<PlaybackCandidate>),
+    Autoplay(u64, Reply<PlaybackCandidate>),
+    Stop(Reply<()>),
+    Resume(u64, Reply<Option<PlaybackCandidate>>),
+    Restore(Reply<()>),
+    Reload(Reply<()>),
+    Reconcile(Observation, Reply<Option<Mismatch>>),
+    /// with the duration of the fade-out
+    Shutdown(Duration, Reply<()>),
+}
+
+/// What playback is requested for.
+#[derive(Deserialize, ToSchema, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaybackTarget {
+    /// the media source with this id
+    Id(u64),
+    /// the n-th media source, counting from 1
+    Preset(usize),
+    /// the media source with this name
+    Name(String),
+    /// the stored source with this link, other links only if ad-hoc links are allowed
+    Link(String),
+}
+
+impl fmt::Display for PlaybackTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlaybackTarget::Id(id) => write!(f, "id {}", id),
+            PlaybackTarget::Preset(preset) => write!(f, "preset {}", preset),
+            PlaybackTarget::Name(name) => write!(f, "name '{}'", name),
+            PlaybackTarget::Link(link) => write!(f, "link {}", link),
+        }
+    }
+}
+
+/// What the player is supposed to play. Only stored sources are remembered across restarts.
+#[derive(Serialize, ToSchema, Clone, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Current {
+    /// id of a stored media source
+    Source(u64),
+    /// an ad-hoc link
+    Link(String),
+}
+
+/// Cheap to clone handle to the `PlayerService` that can be used from any thread.
+#[derive(Clone)]
+pub struct PlayerHandle {
+    commands: mpsc::Sender<Queued>,
+}
+
+impl PlayerHandle {
+    async fn request<T>(
+        &self,
+        command: impl FnOnce(Reply<T>) -> Command,
+    ) -> Result<T, HomeRadioError> {
+        let (reply, answer) = oneshot::channel();
+        self.commands
+            .send((command(reply), Span::current()))
+            .await
+            .map_err(|_| HomeRadioError::PlayerServiceStopped)?;
+        answer
+            .await
+            .map_err(|_| HomeRadioError::PlayerServiceStopped)?
+    }
+
+    /// All media sources, with the one that is currently playing marked.
+    pub async fn media_sources(&self) -> Result<Vec<MediaSource>, HomeRadioError> {
+        self.request(Command::GetMediaSources).await
+    }
+
+    /// Stores the source and returns it with the id it was stored under.
+    pub async fn add_media_source(
+        &self,
+        source: MediaSource,
+    ) -> Result<MediaSource, HomeRadioError> {
+        self.request(|reply| Command::AddMediaSource(source, reply))
+            .await
+    }
+
+    pub async fn volume(&self) -> Result<u16, HomeRadioError> {
+        self.request(Command::GetVolume).await
+    }
+
+    /// Stores the volume and applies it to the player.
+    pub async fn set_volume(&self, volume: u16) -> Result<(), HomeRadioError> {
+        self.request(|reply| Command::SetVolume(volume, reply))
+            .await
+    }
+
+    pub async fn current_media_source(&self) -> Result<Option<Current>, HomeRadioError> {
+        self.request(Command::GetCurrentMediaSource).await
+    }
+
+    /// Plays the first playable link of the target and remembers it as the current one.
+    pub async fn start(&self, target: PlaybackTarget) -> Result<PlaybackCandidate, HomeRadioError> {
+        self.request(|reply| Command::Start(target, reply)).await
+    }
+
+    /// Starts the source `id` like `start`, but if it can't be played it stays the current one
+    /// and the watchdog keeps retrying it.
+    pub async fn autoplay(&self, id: u64) -> Result<PlaybackCandidate, HomeRadioError> {
+        self.request(|reply| Command::Autoplay(id, reply)).await
+    }
+
+    pub async fn stop(&self) -> Result<(), HomeRadioError> {
+        self.request(Command::Stop).await
+    }
+
+    /// Replays the current source for the watchdog, unless playback was stopped or
+    /// switched since the watchdog saw generation `generation`.
+    pub async fn resume(
+        &self,
+        generation: u64,
+    ) -> Result<Option<PlaybackCandidate>, HomeRadioError> {
+        self.request(|reply| Command::Resume(generation, reply))
+            .await
+    }
+
+    /// Brings a freshly started player back to the stored volume and resumes playback that was running before.
+    pub async fn restore(&self) -> Result<(), HomeRadioError> {
+        self.request(Command::Restore).await
+    }
+
+    /// Reads the state from the storage again, e.g. after the state files were edited by hand.
+    pub async fn reload(&self) -> Result<(), HomeRadioError> {
+        self.request(Command::Reload).await
+    }
+
+    /// Compares the current source with what the player was observed doing and corrects the state where possible.
+    pub async fn reconcile(
+        &self,
+        observation: Observation,
+    ) -> Result<Option<Mismatch>, HomeRadioError> {
+        self.request(|reply| Command::Reconcile(observation, reply))
+            .await
+    }
+
+    /// Fades out and stops playback if `fade_out` isn't zero, writes the state once more and stops the service.
+    /// Commands sent afterwards fail with `PlayerServiceStopped`.
+    pub async fn shutdown(&self, fade_out: Duration) -> Result<(), HomeRadioError> {
+        self.request(|reply| Command::Shutdown(fade_out, reply))
+            .await
+    }
+}
+
+/// The stored state, kept in memory so reads don't touch the storage.
+#[derive(PartialEq)]
+struct PlayerState {
+    sources: Vec<MediaSource>,
+    volume: u16,
+    current: Option<Current>,
+}
+
+impl PlayerState {
+    async fn load(storage: &dyn Storage) -> Result<Self, HomeRadioError> {
+        Ok(PlayerState {
+            sources: storage.get_media_sources().await?,
+            volume: storage.get_volume().await?,
+            current: storage
+                .get_current_media_source()
+                .await?
+                .map(Current::Source),
+        })
+    }
+
+    /// The links to try for `current`, in order.
+    fn candidates(&self, current: &Current) -> Vec<PlaybackCandidate> {
+        match current {
+            Current::Source(id) => playback_candidates(&self.sources, *id),
+            Current::Link(link) => vec![PlaybackCandidate {
+                source: None,
+                link: link.clone(),
+            }],
+        }
+    }
+
+    /// The primary link of the current source, for reports.
+    fn current_link(&self) -> Option<String> {
+        match self.current.as_ref()? {
+            Current::Source(id) => self
+                .sources
+                .iter()
+                .find(|src| src.id == *id)
+                .map(|src| src.link.clone()),
+            Current::Link(link) => Some(link.clone()),
+        }
+    }
+}
+
+/// What to do with the outcome of a play attempt.
+enum AttemptKind {
+    Start {
+        current: Current,
+        primary: PlaybackCandidate,
+        /// keep `current` and leave it to the watchdog if none of the links plays
+        retry: bool,
+        reply: Reply<PlaybackCandidate>,
+    },
+    Resume {
+        current: Current,
+        reply: Reply<Option<PlaybackCandidate>>,
+    },
+    Restore(Reply<()>),
+}
+
+/// Trying the links of a source can take minutes, so it runs in its own task
+/// and the service keeps answering commands in the meantime.
+struct Attempt {
+    kind: AttemptKind,
+    /// the volume the links are played with
+    volume: u16,
+    /// the span of the requester, for the log lines of applying the outcome
+    span: Span,
+    task: JoinHandle<Result<PlaybackCandidate, HomeRadioError>>,
+}
+
+/// Owns the storage and the playback state. It handles one command at a time,
+/// so e.g. starting a source and recording it as the current one can't interleave with a stop.
+/// Play attempts run on their own, only their outcome is applied like a command,
+/// and a new start, a stop or the shutdown aborts the running one.
+/// The state is loaded once and every change is written through to the storage.
+pub struct PlayerService {
+    storage: Box<dyn Storage>,
+    state: PlayerState,
+    srvc: RemoteMediaService,
+    watchdog: WatchdogHandle,
+    events: EventBus,
+    adhoc: AdhocPolicy,
+    commands: mpsc::Receiver<Queued>,
+    attempt: Option<Attempt>,
+    /// when the current source changed last, older observations of the player are outdated
+    changed_at: Instant,
+}
+
+impl PlayerService {
+    pub async fn new(
+        storage: Box<dyn Storage>,
+        srvc: RemoteMediaService,
+        watchdog: WatchdogHandle,
+        events: EventBus,
+        adhoc: AdhocPolicy,
+    ) -> Result<(Self, PlayerHandle), HomeRadioError> {
+        let state = PlayerState::load(storage.as_ref()).await?;
+        let (sender, commands) = mpsc::channel(QUEUE_SIZE);
+        let service = PlayerService {
+            storage,
+            state,
+            srvc,
+            watchdog,
+            events,
+            adhoc,
+            commands,
+            attempt: None,
+            changed_at: Instant::now(),
+        };
+        Ok((service, PlayerHandle { commands: sender }))
+    }
+
+    pub async fn run(mut self) {
+        loop {
+            tokio::select! {
+                queued = self.commands.recv() => match queued {
+                    Some((command, span)) => self.handle(command).instrument(span).await,
+                    None => break,
+                },
+                outcome = attempt_finished(&mut self.attempt) => {
+                    if let Some(attempt) = self.attempt.take() {
+                        let span = attempt.span.clone();
+                        self.apply_attempt(attempt, outcome).instrument(span).await;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle(&mut self, command: Command) {
+        // a requester that went away in the meantime doesn't need its answer
+        match command {
+            Command::GetMediaSources(reply) => {
+                let _ = reply.send(Ok(self.media_sources()));
+            }
+            Command::AddMediaSource(source, reply) => {
+                let _ = reply.send(self.add_media_source(source).await);
+            }
+            Command::GetVolume(reply) => {
+                let _ = reply.send(Ok(self.state.volume));
+            }
+            Command::SetVolume(volume, reply) => {
+                let _ = reply.send(self.set_volume(volume).await);
+            }
+            Command::GetCurrentMediaSource(reply) => {
+                let _ = reply.send(Ok(self.state.current.clone()));
+            }
+            Command::Start(target, reply) => match self.resolve(&target) {
+                Ok(current) => self.start(current, false, reply),
+                Err(e) => {
+                    let _ = reply.send(Err(e));
+                }
+            },
+            Command::Autoplay(id, reply) => match self.resolve(&PlaybackTarget::Id(id)) {
+                Ok(current) => self.start(current, true, reply),
+                Err(e) => {
+                    let _ = reply.send(Err(e));
+                }
+            },
+            Command::Stop(reply) => {
+                let _ = reply.send(self.stop().await);
+            }
+            Command::Resume(generation, reply) => self.resume(generation, reply),
+            Command::Restore(reply) => self.restore(reply).await,
+            Command::Reload(reply) => {
+                let _ = reply.send(self.reload().await);
+            }
+            Command::Reconcile(observation, reply) => {
+                let _ = reply.send(self.reconcile(observation).await);
+            }
+            Command::Shutdown(fade_out, reply) => {
+                // the commands already queued are still handled
+                self.commands.close();
+                let _ = reply.send(self.shutdown(fade_out).await);
+            }
+        }
+    }
+
+    fn media_sources(&self) -> Vec<MediaSource> {
+        let mut media_sources = self.state.sources.clone();
+        let current_source = match &self.state.current {
+            Some(Current::Source(id)) => *id,
+            _ => return media_sources,
+        };
+
+        let active = self.watchdog.active();
+        for src in media_sources.iter_mut() {
+            if current_source == src.id {
+                src.currently_playing = Some(true);
+                if let Some(active) = &active {
+                    src.active_link = Some(active.link.clone());
+                    src.active_source = active.source.clone();
+                }
+            } else {
+                src.currently_playing = None;
+            }
+        }
+        media_sources
+    }
+
+    async fn add_media_source(
+        &mut self,
+        source: MediaSource,
+    ) -> Result<MediaSource, HomeRadioError> {
+        // ids are handed out by the storage, a client can only refer to existing ones
+        if source.id != 0 {
+            if !self.state.sources.iter().any(|src| src.id == source.id) {
+                return Err(HomeRadioError::UnknownMediaSource(format!(
+                    "id {}",
+                    source.id
+                )));
+            }
+            if self
+                .state
+                .sources
+                .iter()
+                .any(|src| src.id != source.id && src.name == source.name)
+            {
+                return Err(HomeRadioError::InvalidInput(format!(
+                    "there already is a media source named '{}'",
+                    source.name
+                )));
+            }
+        }
+        let (id, name) = (source.id, source.name.clone());
+        self.storage.add_media_source(source).await?;
+        // the storage decides where the source ends up, so take its view of the list
+        self.state.sources = self.storage.get_media_sources().await?;
+        self.events.publish(Event::Sources);
+        self.state
+            .sources
+            .iter()
+            .find(|src| {
+                if id == 0 {
+                    src.name == name
+                } else {
+                    src.id == id
+                }
+            })
+            .cloned()
+            .ok_or_else(|| HomeRadioError::UnknownMediaSource(format!("name '{}'", name)))
+    }
+
+    async fn set_volume(&mut self, volume: u16) -> Result<(), HomeRadioError> {
+        self.storage.set_volume(volume).await?;
+        self.state.volume = volume;
+        self.events.publish(Event::Volume { volume });
+        info!("default volume set to {}", volume);
+        self.srvc.set_volume(volume).await
+    }
+
+    /// Finds what `target` refers to, ad-hoc links have to pass the policy.
+    fn resolve(&self, target: &PlaybackTarget) -> Result<Current, HomeRadioError> {
+        let sources = &self.state.sources;
+        let source = match target {
+            PlaybackTarget::Id(id) => sources.iter().find(|src| src.id == *id),
+            PlaybackTarget::Preset(preset) => preset.checked_sub(1).and_then(|i| sources.get(i)),
+            PlaybackTarget::Name(name) => sources.iter().find(|src| &src.name == name),
+            PlaybackTarget::Link(link) => match sources.iter().find(|src| &src.link == link) {
+                Some(src) => Some(src),
+                None => {
+                    self.adhoc.check(link)?;
+                    return Ok(Current::Link(link.clone()));
+                }
+            },
+        };
+        source
+            .map(|src| Current::Source(src.id))
+            .ok_or_else(|| HomeRadioError::UnknownMediaSource(target.to_string()))
+    }
+
+    /// Runs `play_first` for `candidates` in its own task, `kind` decides what happens once it is done.
+    fn spawn_attempt(&mut self, candidates: Vec<PlaybackCandidate>, kind: AttemptKind) {
+        let srvc = self.srvc.clone();
+        let volume = self.state.volume;
+        let task = actix_web::rt::spawn(
+            async move { srvc.play_first(&candidates, volume).await.cloned() }.in_current_span(),
+        );
+        self.attempt = Some(Attempt {
+            kind,
+            volume,
+            span: Span::current(),
+            task,
+        });
+    }
+
+    /// Aborts the running play attempt, a waiting start learns that it was superseded.
+    fn cancel_attempt(&mut self) {
+        let attempt = match self.attempt.take() {
+            Some(attempt) => attempt,
+            None => return,
+        };
+        attempt.task.abort();
+        match attempt.kind {
+            AttemptKind::Start { reply, .. } => {
+                let _ = reply.send(Err(HomeRadioError::PlaybackSuperseded));
+            }
+            AttemptKind::Resume { reply, .. } => {
+                let _ = reply.send(Ok(None));
+            }
+            AttemptKind::Restore(reply) => {
+                let _ = reply.send(Ok(()));
+            }
+        }
+    }
+
+    async fn apply_attempt(
+        &mut self,
+        attempt: Attempt,
+        outcome: Result<Result<PlaybackCandidate, HomeRadioError>, JoinError>,
+    ) {
+        let outcome = outcome.unwrap_or_else(|e| Err(e.into()));
+        // the volume may have been changed while the links were tried
+        if outcome.is_ok() && attempt.volume != self.state.volume {
+            if let Err(e) = self.srvc.set_volume(self.state.volume).await {
+                error!("could not apply the volume: {}", e);
+            }
+        }
+        match attempt.kind {
+            AttemptKind::Start {
+                current,
+                primary,
+                retry,
+                reply,
+            } => {
+                let outcome = match outcome {
+                    Ok(active) => {
+                        if active.link != primary.link {
+                            info!("playing {} instead of {}", &active.link, &primary.link);
+                        }
+                        self.make_current(current, active.clone()).await;
+                        Ok(active)
+                    }
+                    Err(e) if retry => {
+                        warn!(
+                            "keeping {} as the current source, the watchdog retries it",
+                            primary.source.as_ref().unwrap_or(&primary.link)
+                        );
+                        self.make_current(current, primary).await;
+                        Err(e)
+                    }
+                    Err(e) => {
+                        self.start_failed().await;
+                        Err(e)
+                    }
+                };
+                let _ = reply.send(outcome);
+            }
+            AttemptKind::Resume { current, reply } => {
+                if let Ok(active) = &outcome {
+                    self.events.publish(Event::Playback {
+                        current: Some(current),
+                        active: Some(active.clone()),
+                    });
+                }
+                let _ = reply.send(outcome.map(Some));
+            }
+            AttemptKind::Restore(reply) => {
+                let outcome = outcome.map(|active| {
+                    self.watchdog.arm(active);
+                    self.playback_changed();
+                });
+                let _ = reply.send(outcome);
+            }
+        }
+    }
+
+    fn start(&mut self, current: Current, retry: bool, reply: Reply<PlaybackCandidate>) {
+        let candidates = self.state.candidates(&current);
+        let primary = match candidates.first() {
+            Some(primary) => primary.clone(),
+            None => {
+                let _ = reply.send(Err(HomeRadioError::NoPlayableSource));
+                return;
+            }
+        };
+        info!(
+            "starting playback of {}",
+            primary.source.as_ref().unwrap_or(&primary.link)
+        );
+        self.cancel_attempt();
+        self.watchdog.disarm();
+        self.spawn_attempt(
+            candidates,
+            AttemptKind::Start {
+                current,
+                primary,
+                retry,
+                reply,
+            },
+        );
+    }
+
+    /// Records `current` as the source that plays `active` and lets the watchdog look after it.
+    async fn make_current(&mut self, current: Current, active: PlaybackCandidate) {
+        // ad-hoc links are not resumed after a restart
+        let stored = match &current {
+            Current::Source(id) => self.storage.set_current_media_source(*id).await,
+            Current::Link(_) => self.storage.remove_current_media_source().await,
+        };
+        if let Err(e) = stored {
+            error!("{}", e);
+        }
+        self.state.current = Some(current);
+        self.watchdog.arm(active);
+        self.playback_changed();
+    }
+
+    /// After a failed start nothing is current anymore. The previous source may still be playing
+    /// if every link failed before reaching the player, so it is stopped as well.
+    async fn start_failed(&mut self) {
+        if self.state.current.take().is_none() {
+            return;
+        }
+        if let Err(e) = self.storage.remove_current_media_source().await {
+            error!("{}", e);
+        }
+        self.playback_changed();
+        if let Err(e) = self.srvc.stop().await {
+            error!("could not stop playback: {}", e);
+        }
+    }
+
+    async fn stop(&mut self) -> Result<(), HomeRadioError> {
+        self.cancel_attempt();
+        self.watchdog.disarm();
+        self.storage.remove_current_media_source().await?;
+        self.state.current = None;
+        self.playback_changed();
+        self.srvc.stop().await
+    }
+
+    async fn shutdown(&mut self, fade_out: Duration) -> Result<(), HomeRadioError> {
+        self.cancel_attempt();
+        self.watchdog.disarm();
+        if !fade_out.is_zero() && self.state.current.is_some() {
+            info!("fading out over {:?}", fade_out);
+            if let Err(e) = self.srvc.fade_out(self.state.volume, fade_out).await {
+                error!("could not fade out: {}", e);
+            }
+            if let Err(e) = self.srvc.stop().await {
+                error!("could not stop playback: {}", e);
+            }
+        }
+        // every change is written through, this catches writes that failed
+        self.storage.set_volume(self.state.volume).await?;
+        match &self.state.current {
+            Some(Current::Source(id)) => self.storage.set_current_media_source(*id).await,
+            _ => self.storage.remove_current_media_source().await,
+        }
+    }
+
+    fn resume(&mut self, generation: u64, reply: Reply<Option<PlaybackCandidate>>) {
+        let current = match &self.state.current {
+            // a running attempt plays the current source already
+            Some(current)
+                if self.attempt.is_none() && self.watchdog.generation() == Some(generation) =>
+            {
+                current.clone()
+            }
+            _ => {
+                let _ = reply.send(Ok(None));
+                return;
+            }
+        };
+        let candidates = self.state.candidates(&current);
+        self.spawn_attempt(candidates, AttemptKind::Resume { current, reply });
+    }
+
+    async fn restore(&mut self, reply: Reply<()>) {
+        if let Err(e) = self.srvc.set_volume(self.state.volume).await {
+            let _ = reply.send(Err(e));
+            return;
+        }
+        // only resume playback that was running when vlc went away
+        let current = match &self.state.current {
+            Some(current) if self.attempt.is_none() && self.watchdog.is_armed() => current.clone(),
+            _ => {
+                let _ = reply.send(Ok(()));
+                return;
+            }
+        };
+        info!(
+            "restoring playback of {}",
+            self.state.current_link().unwrap_or_default()
+        );
+        let candidates = self.state.candidates(&current);
+        self.spawn_attempt(candidates, AttemptKind::Restore(reply));
+    }
+
+    /// Replaces the state with the one in the storage. Running playback isn't touched.
+    async fn reload(&mut self) -> Result<(), HomeRadioError> {
+        let mut state = PlayerState::load(self.storage.as_ref()).await?;
+        // ad-hoc links only live in memory
+        if let (None, Some(Current::Link(_))) = (&state.current, &self.state.current) {
+            state.current = self.state.current.clone();
+        }
+        // the watcher also sees the changes written by this service
+        if state != self.state {
+            if state.sources != self.state.sources {
+                self.events.publish(Event::Sources);
+            }
+            if state.volume != self.state.volume {
+                self.events.publish(Event::Volume {
+                    volume: state.volume,
+                });
+            }
+            info!(
+                "reloaded state: {} media sources, volume {}",
+                state.sources.len(),
+                state.volume
+            );
+            if state.current != self.state.current {
+                self.changed_at = Instant::now();
+            }
+            self.state = state;
+        }
+        Ok(())
+    }
+
+    async fn reconcile(
+        &mut self,
+        observation: Observation,
+    ) -> Result<Option<Mismatch>, HomeRadioError> {
+        // the player is expected to differ from the state while links are tried,
+        // and what it did before the last change says nothing about the state now
+        if self.attempt.is_some() || observation.observed_at < self.changed_at {
+            return Ok(None);
+        }
+
+        let stored = self.state.current_link();
+        match (self.state.current.clone(), observation.actual) {
+            // while the watchdog is armed a stopped player is a stall, even if it was stopped
+            // in the ui of vlc, so the watchdog resumes it instead of the state being cleared
+            (Some(current), None) if !self.watchdog.is_armed() => {
+                if let Current::Source(_) = current {
+                    self.storage.remove_current_media_source().await?;
+                }
+                self.state.current = None;
+                self.playback_changed();
+                Ok(Some(Mismatch::new(
+                    MismatchKind::StaleCurrent,
+                    stored,
+                    None,
+                    true,
+                )))
+            }
+            (None, Some(actual)) => {
+                let source = self
+                    .state
+                    .sources
+                    .iter()
+                    .find(|src| src.link == actual || src.alternate_links.contains(&actual));
+                let active = PlaybackCandidate {
+                    source: source.map(|src| src.name.clone()),
+                    link: actual.clone(),
+                };
+                // it is playing already, so the ad-hoc policy doesn't matter here
+                let current = match source {
+                    Some(src) => {
+                        self.storage.set_current_media_source(src.id).await?;
+                        Current::Source(src.id)
+                    }
+                    None => Current::Link(actual.clone()),
+                };
+                self.state.current = Some(current);
+                self.watchdog.arm(active);
+                self.playback_changed();
+                Ok(Some(Mismatch::new(
+                    MismatchKind::UntrackedPlayback,
+                    None,
+                    Some(actual),
+                    true,
+                )))
+            }
+            // the player may report a resolved or normalized link, so this is only reported
+            (Some(current), Some(actual))
+                if !self
+                    .state
+                    .candidates(&current)
+                    .iter()
+                    .any(|candidate| candidate.link == actual) =>
+            {
+                Ok(Some(Mismatch::new(
+                    MismatchKind::OtherStream,
+                    stored,
+                    Some(actual),
+                    false,
+                )))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn playback_changed(&mut self) {
+        self.changed_at = Instant::now();
+        self.events.publish(Event::Playback {
+            current: self.state.current.clone(),
+            active: self.watchdog.active(),
+        });
+    }
+}
+
+/// Waits for the running play attempt, never finishes if there is none.
+async fn attempt_finished(
+    attempt: &mut Option<Attempt>,
+) -> Result<Result<PlaybackCandidate, HomeRadioError>, JoinError> {
+    match attempt {
+        Some(attempt) => (&mut attempt.task).await,
+        None => future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::test_support::source;
+
+    /// Keeps the state in memory.
+    #[derive(Default)]
+    struct FakeStorage {
+        sources: Vec<MediaSource>,
+        current: Mutex<Option<u64>>,
+    }
+
+    #[async_trait]
+    impl Storage for FakeStorage {
+        async fn get_media_sources(&self) -> Result<Vec<MediaSource>, HomeRadioError> {
+            Ok(self.sources.clone())
+        }
+
+        async fn add_media_source(&self, _: MediaSource) -> Result<(), HomeRadioError> {
+            unimplemented!()
+        }
+
+        async fn get_volume(&self) -> Result<u16, HomeRadioError> {
+            Ok(100)
+        }
+
+        async fn set_volume(&self, _: u16) -> Result<(), HomeRadioError> {
+            unimplemented!()
+        }
+
+        async fn get_current_media_source(&self) -> Result<Option<u64>, HomeRadioError> {
+            Ok(*self.current.lock().unwrap())
+        }
+
+        async fn set_current_media_source(&self, id: u64) -> Result<(), HomeRadioError> {
+            *self.current.lock().unwrap() = Some(id);
+            Ok(())
+        }
+
+        async fn remove_current_media_source(&self) -> Result<(), HomeRadioError> {
+            *self.current.lock().unwrap() = None;
+            Ok(())
+        }
+    }
+
+    /// A service with the sources `dlf` (id 1) and `wdr` (id 2) and `current` stored as playing.
+    /// Reconciling doesn't talk to vlc, so nothing listens on its port.
+    async fn player_service(current: Option<u64>) -> PlayerService {
+        let sources = [
+            ("dlf", "http://dlf.example/live"),
+            ("wdr", "http://wdr.example/live"),
+        ]
+        .iter()
+        .zip(1..)
+        .map(|(&(name, link), id)| MediaSource {
+            id,
+            ..source(name, link)
+        })
+        .collect();
+        let storage = FakeStorage {
+            sources,
+            current: Mutex::new(current),
+        };
+        let srvc = RemoteMediaService::new_with_auth("127.0.0.1".into(), "9".into(), "".into());
+        let (service, _) = PlayerService::new(
+            Box::new(storage),
+            srvc,
+            WatchdogHandle::default(),
+            EventBus::default(),
+            AdhocPolicy::default(),
+        )
+        .await
+        .unwrap();
+        service
+    }
+
+    /// What the player answered just now.
+    fn playing(actual: Option<&str>) -> Observation {
+        Observation {
+            actual: actual.map(String::from),
+            observed_at: Instant::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn stopped_player_clears_a_current_source_nobody_watches() {
+        let mut service = player_service(Some(1)).await;
+
+        let mismatch = service.reconcile(playing(None)).await.unwrap().unwrap();
+
+        assert_eq!(mismatch.kind, MismatchKind::StaleCurrent);
+        assert_eq!(mismatch.stored.as_deref(), Some("http://dlf.example/live"));
+        assert!(mismatch.corrected);
+        assert_eq!(service.state.current, None);
+        assert_eq!(
+            service.storage.get_current_media_source().await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn stopped_player_is_left_to_an_armed_watchdog() {
+        let mut service = player_service(Some(1)).await;
+        service.watchdog.arm(PlaybackCandidate {
+            source: Some("dlf".into()),
+            link: "http://dlf.example/live".into(),
+        });
+
+        let mismatch = service.reconcile(playing(None)).await.unwrap();
+
+        assert!(mismatch.is_none());
+        assert_eq!(service.state.current, Some(Current::Source(1)));
+    }
+
+    #[tokio::test]
+    async fn playback_started_elsewhere_becomes_the_current_source() {
+        let mut service = player_service(None).await;
+
+        let observation = playing(Some("http://wdr.example/live"));
+        let mismatch = service.reconcile(observation).await.unwrap().unwrap();
+
+        assert_eq!(mismatch.kind, MismatchKind::UntrackedPlayback);
+        assert!(mismatch.corrected);
+        assert_eq!(service.state.current, Some(Current::Source(2)));
+        assert_eq!(
+            service.storage.get_current_media_source().await.unwrap(),
+            Some(2)
+        );
+        assert!(service.watchdog.is_armed());
+
+        // a link that isn't stored is tracked as an ad-hoc link
+        let mut service = player_service(None).await;
+        let observation = playing(Some("http://other.example/stream"));
+        service.reconcile(observation).await.unwrap();
+        let link = Current::Link("http://other.example/stream".into());
+        assert_eq!(service.state.current, Some(link));
+        assert_eq!(
+            service.storage.get_current_media_source().await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn other_stream_is_only_reported() {
+        let mut service = player_service(Some(1)).await;
+
+        let observation = playing(Some("http://wdr.example/live"));
+        let mismatch = service.reconcile(observation).await.unwrap().unwrap();
+
+        assert_eq!(mismatch.kind, MismatchKind::OtherStream);
+        assert_eq!(mismatch.actual.as_deref(), Some("http://wdr.example/live"));
+        assert!(!mismatch.corrected);
+        assert_eq!(service.state.current, Some(Current::Source(1)));
+
+        let observation = playing(Some("http://dlf.example/live"));
+        assert!(service.reconcile(observation).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn observation_from_before_a_change_is_ignored() {
+        let mut service = player_service(None).await;
+        let observation = Observation {
+            observed_at: Instant::now() - Duration::from_secs(1),
+            ..playing(Some("http://wdr.example/live"))
+        };
+        // e.g. a stop that was handled while the player was asked
+        service.playback_changed();
+
+        assert!(service.reconcile(observation).await.unwrap().is_none());
+        assert_eq!(service.state.current, None);
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::time::sleep;
+use tracing::{debug, warn};
+use utoipa::ToSchema;
+
+use crate::errors::HomeRadioError;
+
+use super::{unix_time, PlaybackState, PlayerHandle, RemoteMediaService, StatusHandle};
+
+const MAX_MISMATCHES: usize = 20;
+
+/// e.g. vlc is still starting, don't wait for the whole interval until the next check
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, ToSchema, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MismatchKind {
+    /// a source is stored as playing, but the player is stopped and nothing tries to restart it.
+    /// While the watchdog is armed a stopped player is a stall, also when it was stopped in the
+    /// ui of vlc, and the watchdog resumes the source. Playback is stopped through home-radio.
+    StaleCurrent,
+    /// the player plays something that was started outside of home-radio
+    UntrackedPlayback,
+    /// the player plays a link that doesn't belong to the stored source
+    OtherStream,
+}
+
+#[derive(Serialize, ToSchema, Clone)]
+pub struct Mismatch {
+    /// seconds since the unix epoch
+    pub timestamp: u64,
+    pub kind: MismatchKind,
+    /// the current source according to the stored state
+    pub stored: Option<String>,
+    /// the link the player is on
+    pub actual: Option<String>,
+    /// whether the stored state was changed to match the player
+    pub corrected: bool,
+}
+
+impl Mismatch {
+    pub fn new(
+        kind: MismatchKind,
+        stored: Option<String>,
+        actual: Option<String>,
+        corrected: bool,
+    ) -> Self {
+        Mismatch {
+            timestamp: unix_time(),
+            kind,
+            stored,
+            actual,
+            corrected,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema, Clone, Default)]
+pub struct ReconcileReport {
+    pub checks: u64,
+    pub corrections: u64,
+    /// seconds since the unix epoch
+    pub last_check: Option<u64>,
+    #[schema(value_type = Vec<Mismatch>)]
+    pub mismatches: VecDeque<Mismatch>,
+}
+
+#[derive(Clone, Default)]
+pub struct ReconcileHandle {
+    inner: Arc<Mutex<ReconcileReport>>,
+}
+
+impl ReconcileHandle {
+    pub fn report(&self) -> ReconcileReport {
+        self.inner.lock().unwrap().clone()
+    }
+
+    fn record(&self, mismatch: Option<Mismatch>) {
+        let mut report = self.inner.lock().unwrap();
+        report.checks += 1;
+        report.last_check = Some(unix_time());
+        let mismatch = match mismatch {
+            Some(mismatch) => mismatch,
+            None => return,
+        };
+        // a mismatch that can't be corrected shows up on every check, keep it once
+        let repeated = report.mismatches.back().is_some_and(|last| {
+            !mismatch.corrected
+                && last.kind == mismatch.kind
+                && last.stored == mismatch.stored
+                && last.actual == mismatch.actual
+        });
+        if repeated {
+            return;
+        }
+        warn!(
+            "state and player disagree ({:?}): stored {:?}, playing {:?}{}",
+            mismatch.kind,
+            mismatch.stored,
+            mismatch.actual,
+            if mismatch.corrected {
+                ", corrected"
+            } else {
+                ""
+            }
+        );
+        if mismatch.corrected {
+            report.corrections += 1;
+        }
+        if report.mismatches.len() == MAX_MISMATCHES {
+            report.mismatches.pop_front();
+        }
+        report.mismatches.push_back(mismatch);
+    }
+}
+
+/// What the player was doing, as seen by the `Reconciler`.
+#[derive(Clone, Debug)]
+pub struct Observation {
+    /// the link the player is on, `None` if it is stopped
+    pub actual: Option<String>,
+    /// when the player was asked, the state may have changed since
+    pub observed_at: Instant,
+}
+
+/// Compares the stored state with what the player actually does, at startup and then periodically.
+/// A stopped player with a running watchdog is left to the watchdog.
+/// The player is asked here, so the player service only compares and corrects.
+pub struct Reconciler {
+    interval: Duration,
+    srvc: RemoteMediaService,
+    status: StatusHandle,
+    player: PlayerHandle,
+    handle: ReconcileHandle,
+}
+
+impl Reconciler {
+    pub fn new(
+        interval: Duration,
+        srvc: RemoteMediaService,
+        player: PlayerHandle,
+        handle: ReconcileHandle,
+    ) -> Self {
+        Reconciler {
+            interval,
+            status: srvc.status(),
+            srvc,
+            player,
+            handle,
+        }
+    }
+
+    pub async fn run(self) {
+        let mut failing = false;
+        loop {
+            let checked = match self.observe().await {
+                Ok(observation) => self.player.reconcile(observation).await,
+                Err(e) => Err(e),
+            };
+            let delay = match checked {
+                Ok(mismatch) => {
+                    failing = false;
+                    self.handle.record(mismatch);
+                    self.interval
+                }
+                Err(e) => {
+                    // only the first of a row of failures, e.g. while vlc is down
+                    if failing {
+                        debug!("could not compare state and player: {}", e);
+                    } else {
+                        warn!("could not compare state and player, retrying: {}", e);
+                    }
+                    failing = true;
+                    self.interval.min(RETRY_DELAY)
+                }
+            };
+            sleep(delay).await;
+        }
+    }
+
+    /// Takes the state from the latest poll and asks the player for its link if it isn't stopped.
+    async fn observe(&self) -> Result<Observation, HomeRadioError> {
+        // none before the first poll and while the player can't be reached, then ask right away
+        let snapshot = match self.status.current() {
+            Some(snapshot) => snapshot,
+            None => self
+                .status
+                .poll(RETRY_DELAY)
+                .await
+                .ok_or(HomeRadioError::VLCServerUnhealthy)?,
+        };
+        let actual = if snapshot.state == PlaybackState::Stopped {
+            None
+        } else {
+            self.srvc.current_uri().await?
+        };
+        Ok(Observation {
+            actual,
+            observed_at: snapshot.polled_at,
+        })
+    }
+}
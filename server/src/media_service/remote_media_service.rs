@@ -1,16 +1,44 @@
+use awc::{
+    error::{ConnectError, SendRequestError},
+    http::header::CONTENT_TYPE,
+};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, info_span, warn, Instrument};
 
-use tokio::{self, time::sleep};
+use tokio::{
+    self,
+    sync::watch,
+    time::{sleep, timeout, timeout_at},
+};
 
-use crate::errors::HomeRadioError;
+use crate::{backend::PlaybackCandidate, errors::HomeRadioError, metrics::Metrics};
+
+use super::{status_channel, PlaybackState, PlayerSnapshot, StatusHandle};
+
+/// how long a single link may take until the player reports it as playing
+pub const DEFAULT_STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+/// how long all links and fallbacks of a source may take together
+pub const DEFAULT_PLAY_TIMEOUT: Duration = Duration::from_secs(90);
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// number of volume changes of a fade-out
+const FADE_STEPS: u32 = 20;
 
 #[derive(Clone)]
 pub struct RemoteMediaService {
     base_url: String,
-    host: String,
-    port: String,
     client: awc::Client,
+    probe_client: awc::Client,
+    startup_timeout: Duration,
+    play_timeout: Duration,
+    snapshots: Arc<watch::Sender<Option<PlayerSnapshot>>>,
+    status: StatusHandle,
+    metrics: Metrics,
 }
 
 impl RemoteMediaService {
@@ -18,14 +46,46 @@ impl RemoteMediaService {
         let client = awc::Client::builder()
             .basic_auth("", Some(&pwd[..]))
             .finish();
+        let probe_client = awc::Client::builder().timeout(PROBE_TIMEOUT).finish();
         let base_url = format!("http://{}:{}", host, port);
+        let (snapshots, status) = status_channel();
         RemoteMediaService {
-            host,
-            port,
-            client,
             base_url,
+            client,
+            probe_client,
+            startup_timeout: DEFAULT_STARTUP_TIMEOUT,
+            play_timeout: DEFAULT_PLAY_TIMEOUT,
+            snapshots: Arc::new(snapshots),
+            status,
+            metrics: Metrics::default(),
         }
     }
+
+    pub fn with_startup_timeout(mut self, startup_timeout: Duration) -> Self {
+        self.startup_timeout = startup_timeout;
+        self
+    }
+
+    pub fn with_play_timeout(mut self, play_timeout: Duration) -> Self {
+        self.play_timeout = play_timeout;
+        self
+    }
+
+    /// Counts the play attempts in `metrics` instead of a private registry.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Snapshots of the player, kept up to date by a `StatusPoller` running on any clone of this service.
+    pub fn status(&self) -> StatusHandle {
+        self.status.clone()
+    }
+
+    pub(super) fn publish(&self, snapshot: Option<PlayerSnapshot>) {
+        self.snapshots.send_replace(snapshot);
+    }
+
     async fn remote_command(
         &self,
         command: &str,
@@ -46,25 +106,135 @@ impl RemoteMediaService {
         Ok(())
     }
 
-    pub async fn play(&self, url: &str, volume: u16) -> Result<(), HomeRadioError> {
+    /// Plays `url` and returns how long it took until the player reported playing.
+    pub async fn play(&self, url: &str, volume: u16) -> Result<Duration, HomeRadioError> {
+        let requested = Instant::now();
+        self.probe(url).await?;
         self.remote_command("pl_empty", &[]).await?;
         self.remote_command("in_play", &[("input", url), ("option", "novideo")])
             .await?;
-        let state = self.get_status().await?.state;
-        if &state == "stopped" {
-            loop {
-                let status = self.get_status().await?;
-                dbg!(&status.state);
-                if &status.state[..] == "playing" {
+        // the previous stream may still be reported as playing by a poll sent before the switch
+        let playing = self.status.wait_until(Instant::now(), |snapshot| {
+            snapshot.state == PlaybackState::Playing
+        });
+        let started = timeout(self.startup_timeout, playing).await;
+        if started.is_err() {
+            // don't let the player start on its own after we reported the failure
+            self.remote_command("pl_stop", &[]).await?;
+            return Err(HomeRadioError::StartupTimeout {
+                url: url.into(),
+                seconds: self.startup_timeout.as_secs(),
+            });
+        }
+        started.unwrap()?;
+        let first_audio = requested.elapsed();
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        self.set_volume(volume).await?;
+
+        Ok(first_audio)
+    }
+
+    /// Requests the stream once before handing it to the player, because the player itself
+    /// doesn't tell why a stream can't be opened.
+    /// Only clear failures are reported, everything else is left for the player to try.
+    async fn probe(&self, url: &str) -> Result<(), HomeRadioError> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Ok(());
+        }
+        let response = match self.probe_client.get(url).send().await {
+            Ok(response) => response,
+            Err(SendRequestError::Connect(ConnectError::Resolver(_)))
+            | Err(SendRequestError::Connect(ConnectError::NoRecords)) => {
+                return Err(HomeRadioError::StreamDnsError(url.into()))
+            }
+            Err(SendRequestError::Connect(e)) => {
+                return Err(HomeRadioError::StreamUnreachable {
+                    url: url.into(),
+                    reason: e.to_string(),
+                })
+            }
+            Err(e) => {
+                debug!("probing {} failed, leaving it to the player: {}", url, e);
+                return Ok(());
+            }
+        };
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(HomeRadioError::StreamHttpStatus {
+                url: url.into(),
+                status: status.as_u16(),
+            });
+        }
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        if let Some(content_type) = content_type {
+            if !is_playable_content_type(content_type) {
+                return Err(HomeRadioError::UnsupportedCodec {
+                    url: url.into(),
+                    content_type: content_type.into(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Tries the candidates in order and returns the first one that started playing,
+    /// giving up on the rest once the play timeout is over.
+    /// If none of them plays, the error of the first candidate is returned.
+    pub async fn play_first<'a>(
+        &self,
+        candidates: &'a [PlaybackCandidate],
+        volume: u16,
+    ) -> Result<&'a PlaybackCandidate, HomeRadioError> {
+        let deadline = tokio::time::Instant::now() + self.play_timeout;
+        let mut first_err = None;
+        for candidate in candidates {
+            let span = info_span!(
+                "play",
+                link = %candidate.link,
+                source = candidate.source.as_deref().unwrap_or_default()
+            );
+            let played = timeout_at(
+                deadline,
+                self.play(&candidate.link, volume).instrument(span.clone()),
+            )
+            .await;
+            let played = match played {
+                Ok(played) => played,
+                Err(_) => {
+                    self.metrics.observe_play(candidate.source.as_deref(), None);
+                    warn!(
+                        "giving up on {} and the remaining links after {:?}",
+                        candidate.link, self.play_timeout
+                    );
+                    // the attempt may have been cut off after the player was told to play
+                    if let Err(e) = self.remote_command("pl_stop", &[]).await {
+                        warn!("could not stop the player: {}", e);
+                    }
+                    first_err.get_or_insert(HomeRadioError::PlayTimeout {
+                        seconds: self.play_timeout.as_secs(),
+                    });
                     break;
                 }
-                tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+            };
+            self.metrics
+                .observe_play(candidate.source.as_deref(), played.as_ref().ok().copied());
+            let _entered = span.enter();
+            match played {
+                Ok(first_audio) => {
+                    info!("playing after {:?}", first_audio);
+                    return Ok(candidate);
+                }
+                Err(e) => {
+                    warn!("could not play {}: {}", candidate.link, e);
+                    first_err.get_or_insert(e);
+                }
             }
-            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-            self.set_volume(volume).await?;
         }
-
-        Ok(())
+        Err(first_err.unwrap_or(HomeRadioError::NoPlayableSource))
     }
 
     pub async fn wait_for_healthy(
@@ -94,7 +264,7 @@ impl RemoteMediaService {
         }
         let err = result.err().unwrap();
         match err {
-            HomeRadioError::SendRequestError(ref _sre) => Ok(false),
+            HomeRadioError::SendRequestError(_) => Ok(false),
             HomeRadioError::Io(ref io_err) => match io_err.kind() {
                 std::io::ErrorKind::ConnectionRefused => Ok(false),
                 _ => Err(err),
@@ -115,18 +285,30 @@ impl RemoteMediaService {
         Ok(serde_json::from_str(&String::from_utf8_lossy(&body))?)
     }
 
+    /// Uri of the playlist item the player is on, if any.
+    pub async fn current_uri(&self) -> Result<Option<String>, HomeRadioError> {
+        let body = self
+            .client
+            .get(format!("{}/requests/playlist.json", self.base_url))
+            .send()
+            .await?
+            .body()
+            .await?;
+
+        let playlist: PlaylistNode = serde_json::from_slice(&body)?;
+        Ok(playlist.current_uri().map(String::from))
+    }
+
     pub async fn stop(&self) -> Result<(), HomeRadioError> {
         self.remote_command("pl_empty", &[]).await?;
         let mut query = HashMap::new();
         query.insert("command", "pl_stop");
-        let result = self
-            .client
+        self.client
             .get(format!("{}/requests/status.json", self.base_url))
             .query(&query)
             .map_err(|e| HomeRadioError::UrlEncodedError(Box::new(e)))?
             .send()
             .await?;
-        dbg!(result);
 
         Ok(())
     }
@@ -141,94 +323,76 @@ impl RemoteMediaService {
             .get(format!("{}/requests/status.json", self.base_url))
             .query(&query)
             .map_err(|e| HomeRadioError::UrlEncodedError(Box::new(e)))?;
-        dbg!(&request);
         let result = request.send().await?;
-        dbg!(result);
+        debug!("set the volume to {}: {}", new_vol, result.status());
 
         Ok(())
     }
-}
 
-#[derive(Serialize, Deserialize)]
-pub struct VlcStatus {
-    #[serde(rename = "state")]
-    state: String,
+    /// Lowers the volume from `from` to 0 in even steps over `duration`.
+    pub async fn fade_out(&self, from: u16, duration: Duration) -> Result<(), HomeRadioError> {
+        let step = duration / FADE_STEPS;
+        for i in (0..FADE_STEPS).rev() {
+            self.set_volume((from as u32 * i / FADE_STEPS) as u16)
+                .await?;
+            if i > 0 {
+                sleep(step).await;
+            }
+        }
+        Ok(())
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct Audiofilters {
-    #[serde(rename = "filter_0")]
-    filter_0: String,
+/// Rejects content the player can't make sense of. Web pages are allowed since the player
+/// resolves some of them (e.g. YouTube) to the actual stream.
+fn is_playable_content_type(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let main_type = mime.split('/').next().unwrap_or_default();
+    !matches!(main_type, "image" | "font" | "model")
+        && !matches!(
+            &mime[..],
+            "application/json" | "application/pdf" | "application/zip"
+        )
 }
 
 #[derive(Serialize, Deserialize)]
-pub struct Information {
-    #[serde(rename = "chapter")]
-    chapter: i64,
-
-    #[serde(rename = "chapters")]
-    chapters: Vec<Option<serde_json::Value>>,
-
-    #[serde(rename = "title")]
-    title: i64,
-
-    #[serde(rename = "category")]
-    category: Category,
-
-    #[serde(rename = "titles")]
-    titles: Vec<Option<serde_json::Value>>,
-}
+pub struct VlcStatus {
+    #[serde(rename = "state")]
+    pub state: String,
 
-#[derive(Serialize, Deserialize)]
-pub struct Category {
-    #[serde(rename = "meta")]
-    meta: Meta,
+    /// playback position in seconds, keeps increasing while a live stream delivers data
+    #[serde(rename = "time", default)]
+    pub time: i64,
 
-    #[serde(rename = "Stream 0")]
-    stream_0: Option<Stream0>,
-}
+    #[serde(default)]
+    pub volume: f64,
 
-#[derive(Serialize, Deserialize)]
-pub struct Meta {
-    #[serde(rename = "filename")]
-    filename: String,
+    /// metadata and codec details of the current item, grouped by category
+    #[serde(default)]
+    pub information: serde_json::Value,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct Stream0 {
-    #[serde(rename = "Bitrate")]
-    bitrate: String,
-
-    #[serde(rename = "Codec")]
-    codec: String,
-
-    #[serde(rename = "Bits_pro_Sample")]
-    bits_pro_sample: String,
-
-    #[serde(rename = "Abtastrate")]
-    abtastrate: String,
-
-    #[serde(rename = "Typ")]
-    typ: String,
-
-    #[serde(rename = "KanÃ¤le")]
-    kan_le: String,
+/// Node of the playlist tree of the player, only the item that is playing has `current` set.
+#[derive(Deserialize)]
+struct PlaylistNode {
+    #[serde(default)]
+    uri: Option<String>,
+    #[serde(default)]
+    current: Option<String>,
+    #[serde(default)]
+    children: Vec<PlaylistNode>,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct Videoeffects {
-    #[serde(rename = "hue")]
-    hue: i64,
-
-    #[serde(rename = "saturation")]
-    saturation: i64,
-
-    #[serde(rename = "contrast")]
-    contrast: i64,
-
-    #[serde(rename = "brightness")]
-    brightness: i64,
-
-    #[serde(rename = "gamma")]
-    gamma: i64,
+impl PlaylistNode {
+    fn current_uri(&self) -> Option<&str> {
+        match (&self.current, &self.uri) {
+            (Some(_), Some(uri)) => Some(uri),
+            _ => self.children.iter().find_map(PlaylistNode::current_uri),
+        }
+    }
 }
@@ -0,0 +1,209 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use awc::http::Uri;
+use rand::{distributions::Alphanumeric, Rng};
+
+use crate::{backend::write_atomic, errors::HomeRadioError};
+
+use super::RemoteMediaService;
+
+/// name of the vlc config file in the state dir that holds the password of a spawned vlc
+pub const VLC_CONFIG_FILE: &str = "vlcrc";
+
+/// How to reach the vlc http interface and whether we start vlc ourselves.
+#[derive(Clone)]
+pub struct VlcSettings {
+    pub binary: String,
+    /// address vlc listens on, a spawned vlc is bound to it
+    pub host: String,
+    pub port: String,
+    pub password: String,
+    /// attach to a vlc that is managed by someone else instead of spawning one
+    pub external: bool,
+    pub extra_args: Vec<String>,
+    pub startup_timeout: Duration,
+    pub play_timeout: Duration,
+}
+
+impl VlcSettings {
+    pub fn connect(&self) -> RemoteMediaService {
+        RemoteMediaService::new_with_auth(
+            self.host.clone(),
+            self.port.clone(),
+            self.password.clone(),
+        )
+        .with_startup_timeout(self.startup_timeout)
+        .with_play_timeout(self.play_timeout)
+    }
+
+    /// Writes the config file for a spawned vlc. It carries the password, which would be readable
+    /// by every user in `/proc/<pid>/cmdline` if it were passed as an argument.
+    pub async fn write_config(&self, dir: &Path) -> Result<PathBuf, HomeRadioError> {
+        let path = dir.join(VLC_CONFIG_FILE);
+        let content = format!("[core]\nhttp-password={}\n", self.password);
+        write_atomic(&path, content.as_bytes(), 0o600).await?;
+        Ok(path)
+    }
+
+    /// Arguments for spawning vlc with the http interface as the only way to control it,
+    /// `config` is the file written by `write_config`.
+    pub fn args(&self, config: &Path) -> Vec<String> {
+        let mut args: Vec<String> = [
+            "-I",
+            "http",
+            "--no-video",
+            "--vout",
+            "none",
+            "--config",
+            &config.to_string_lossy(),
+            "--http-host",
+            &self.host,
+            "--http-port",
+            &self.port,
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+}
+
+/// Password for a vlc instance that only lives as long as this process.
+pub fn random_password() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+/// Which links may be played without being stored as a media source first.
+#[derive(Clone, Default)]
+pub struct AdhocPolicy {
+    /// every ad-hoc link is refused unless this is set
+    pub enabled: bool,
+    pub schemes: Vec<String>,
+    /// `*.example.com` matches example.com and its subdomains, an empty list matches every host
+    pub hosts: Vec<String>,
+}
+
+impl AdhocPolicy {
+    pub fn check(&self, link: &str) -> Result<(), HomeRadioError> {
+        if !self.enabled {
+            return Err(HomeRadioError::LinkNotAllowed(link.into()));
+        }
+        let uri: Uri = link
+            .parse()
+            .map_err(|_| HomeRadioError::InvalidInput(format!("'{}' is not a link", link)))?;
+        let scheme = uri.scheme_str().unwrap_or_default();
+        let host = uri.host().unwrap_or_default().to_ascii_lowercase();
+        let allowed = self
+            .schemes
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(scheme))
+            && !host.is_empty()
+            && (self.hosts.is_empty()
+                || self
+                    .hosts
+                    .iter()
+                    .any(|allowed| host_matches(allowed, &host)));
+        if allowed {
+            Ok(())
+        } else {
+            Err(HomeRadioError::LinkNotAllowed(link.into()))
+        }
+    }
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host == domain || host.ends_with(&format!(".{}", domain)),
+        None => host == pattern,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(hosts: &[&str]) -> AdhocPolicy {
+        AdhocPolicy {
+            enabled: true,
+            schemes: vec!["http".into(), "https".into()],
+            hosts: hosts.iter().map(|host| host.to_string()).collect(),
+        }
+    }
+
+    fn allowed(policy: &AdhocPolicy, link: &str) -> bool {
+        policy.check(link).is_ok()
+    }
+
+    #[test]
+    fn disabled_policy_refuses_every_link() {
+        let policy = AdhocPolicy {
+            enabled: false,
+            ..policy(&[])
+        };
+
+        let refused = policy.check("https://radio.example.com/live");
+        assert!(matches!(refused, Err(HomeRadioError::LinkNotAllowed(_))));
+    }
+
+    #[test]
+    fn schemes_and_hosts_are_compared_without_case() {
+        let policy = AdhocPolicy {
+            schemes: vec!["HTTPS".into()],
+            ..policy(&["Radio.Example.com"])
+        };
+
+        assert!(allowed(&policy, "https://radio.example.com/live"));
+        assert!(allowed(&policy, "HTTPS://RADIO.EXAMPLE.COM/live"));
+        assert!(!allowed(&policy, "http://radio.example.com/live"));
+        assert!(!allowed(&policy, "ftp://radio.example.com/live"));
+    }
+
+    #[test]
+    fn wildcard_matches_the_domain_and_its_subdomains() {
+        let policy = policy(&["*.example.com"]);
+
+        assert!(allowed(&policy, "http://example.com/live"));
+        assert!(allowed(&policy, "http://a.example.com/live"));
+        assert!(allowed(&policy, "http://a.b.example.com:8000/live"));
+        assert!(!allowed(&policy, "http://evilexample.com/live"));
+        assert!(!allowed(&policy, "http://example.com.evil.org/live"));
+    }
+
+    #[test]
+    fn user_info_is_not_taken_for_the_host() {
+        let policy = policy(&["radio.example.com"]);
+
+        assert!(!allowed(&policy, "http://radio.example.com@evil.org/live"));
+        assert!(!allowed(
+            &policy,
+            "http://radio.example.com:pw@evil.org/live"
+        ));
+        assert!(allowed(&policy, "http://user@radio.example.com/live"));
+    }
+
+    #[test]
+    fn links_without_a_host_are_refused() {
+        let policy = policy(&[]);
+
+        assert!(!allowed(&policy, "/live.mp3"));
+        assert!(!allowed(&policy, "file:///home/radio/live.mp3"));
+        assert!(!allowed(&policy, "not a link"));
+    }
+
+    #[test]
+    fn empty_host_list_allows_every_host() {
+        let policy = policy(&[]);
+
+        assert!(allowed(&policy, "http://radio.example.com/live"));
+        assert!(allowed(&policy, "https://192.0.2.1:8000/live"));
+    }
+}
@@ -0,0 +1,318 @@
+use std::{
+    cmp::min,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use serde_json::Value;
+use tokio::{
+    sync::{watch, Notify},
+    time::sleep,
+};
+use tracing::debug;
+use utoipa::ToSchema;
+
+use crate::errors::HomeRadioError;
+
+use super::{unix_time, RemoteMediaService, VlcStatus};
+
+pub struct PollerSettings {
+    /// used while someone waits for a change and right after the player changed
+    pub fast_interval: Duration,
+    /// the interval grows up to this while nothing changes
+    pub slow_interval: Duration,
+}
+
+#[derive(Serialize, ToSchema, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaybackState {
+    Playing,
+    Paused,
+    Stopped,
+    Unknown,
+}
+
+#[derive(Serialize, ToSchema, Clone, Default, PartialEq, Debug)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    /// the song a radio station currently announces
+    pub now_playing: Option<String>,
+}
+
+#[derive(Serialize, ToSchema, Clone, Default, PartialEq, Debug)]
+pub struct StreamInfo {
+    pub codec: Option<String>,
+    /// as reported by the player, e.g. `128 kb/s`
+    pub bitrate: Option<String>,
+    pub sample_rate: Option<String>,
+    pub channels: Option<String>,
+}
+
+/// What the player reported on the latest poll.
+#[derive(Serialize, ToSchema, Clone, PartialEq, Debug)]
+pub struct PlayerSnapshot {
+    pub state: PlaybackState,
+    /// in the units of the player, 256 is 100%
+    pub volume: u16,
+    /// playback position in seconds
+    pub position: i64,
+    pub metadata: Metadata,
+    pub stream: Option<StreamInfo>,
+    /// seconds since the unix epoch
+    pub updated_at: u64,
+    /// when the poll was sent, a poll that was on its way during a command may report the state from before it
+    #[serde(skip)]
+    pub polled_at: Instant,
+}
+
+impl From<VlcStatus> for PlayerSnapshot {
+    fn from(status: VlcStatus) -> Self {
+        let state = match &status.state[..] {
+            "playing" => PlaybackState::Playing,
+            "paused" => PlaybackState::Paused,
+            "stopped" => PlaybackState::Stopped,
+            _ => PlaybackState::Unknown,
+        };
+        // vlc sends an empty array instead of an object when a category has no entries
+        let categories = &status.information["category"];
+        let meta = &categories["meta"];
+        let metadata = Metadata {
+            title: text(&meta["title"]),
+            artist: text(&meta["artist"]),
+            now_playing: text(&meta["now_playing"]),
+        };
+        // the keys are translated, a vlc with a german locale reports "Typ", "Abtastrate", ...
+        let stream = categories
+            .as_object()
+            .and_then(|categories| {
+                categories
+                    .values()
+                    .find(|category| field(category, &["Type", "Typ"]).as_deref() == Some("Audio"))
+            })
+            .map(|audio| StreamInfo {
+                codec: field(audio, &["Codec"]),
+                bitrate: field(audio, &["Bitrate"]),
+                sample_rate: field(audio, &["Sample_rate", "Sample rate", "Abtastrate"]),
+                channels: field(audio, &["Channels", "Kanäle"]),
+            });
+        PlayerSnapshot {
+            state,
+            volume: status.volume.round() as u16,
+            position: status.time,
+            metadata,
+            stream,
+            updated_at: unix_time(),
+            polled_at: Instant::now(),
+        }
+    }
+}
+
+fn text(value: &Value) -> Option<String> {
+    value.as_str().map(String::from)
+}
+
+/// The first of the keys present in `category`.
+fn field(category: &Value, keys: &[&str]) -> Option<String> {
+    keys.iter().find_map(|key| text(&category[*key]))
+}
+
+/// Read access to the snapshots published by the `StatusPoller`, cheap to clone.
+#[derive(Clone)]
+pub struct StatusHandle {
+    snapshots: watch::Receiver<Option<PlayerSnapshot>>,
+    wake: Arc<Notify>,
+    waiters: Arc<AtomicUsize>,
+}
+
+/// Makes the poller use its fast interval while it exists.
+struct Waiter<'a>(&'a AtomicUsize);
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl StatusHandle {
+    /// The latest snapshot, `None` if the player couldn't be reached on the last poll.
+    pub fn current(&self) -> Option<PlayerSnapshot> {
+        self.snapshots.borrow().clone()
+    }
+
+    /// Polls the player right away, `None` if it can't be reached within `timeout`.
+    pub async fn poll(&self, timeout: Duration) -> Option<PlayerSnapshot> {
+        let mut snapshots = self.snapshots.clone();
+        snapshots.borrow_and_update();
+        self.wake.notify_one();
+        match tokio::time::timeout(timeout, snapshots.changed()).await {
+            Ok(Ok(())) => snapshots.borrow().clone(),
+            _ => None,
+        }
+    }
+
+    /// Waits for a snapshot of a poll sent after `since` that satisfies `condition`.
+    pub async fn wait_until<F>(
+        &self,
+        since: Instant,
+        condition: F,
+    ) -> Result<PlayerSnapshot, HomeRadioError>
+    where
+        F: Fn(&PlayerSnapshot) -> bool,
+    {
+        let mut snapshots = self.snapshots.clone();
+        snapshots.borrow_and_update();
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        let _waiter = Waiter(&self.waiters);
+        self.wake.notify_one();
+        loop {
+            snapshots
+                .changed()
+                .await
+                .map_err(|_| HomeRadioError::VLCServerUnhealthy)?;
+            if let Some(snapshot) = &*snapshots.borrow() {
+                if snapshot.polled_at >= since && condition(snapshot) {
+                    return Ok(snapshot.clone());
+                }
+            }
+        }
+    }
+
+    /// Receiver that is notified on every poll, for code that follows the player.
+    pub fn subscribe(&self) -> watch::Receiver<Option<PlayerSnapshot>> {
+        self.snapshots.clone()
+    }
+}
+
+/// Creates the channel a `RemoteMediaService` publishes its snapshots on.
+pub(super) fn status_channel() -> (watch::Sender<Option<PlayerSnapshot>>, StatusHandle) {
+    let (snapshots, receiver) = watch::channel(None);
+    let handle = StatusHandle {
+        snapshots: receiver,
+        wake: Arc::new(Notify::new()),
+        waiters: Arc::new(AtomicUsize::new(0)),
+    };
+    (snapshots, handle)
+}
+
+/// Polls the player in the background and publishes what it reports to `RemoteMediaService::status`.
+pub struct StatusPoller {
+    settings: PollerSettings,
+    srvc: RemoteMediaService,
+}
+
+impl StatusPoller {
+    pub fn new(settings: PollerSettings, srvc: RemoteMediaService) -> Self {
+        StatusPoller { settings, srvc }
+    }
+
+    pub async fn run(self) {
+        let handle = self.srvc.status();
+        let mut interval = self.settings.fast_interval;
+        let mut last_state = None;
+        loop {
+            tokio::select! {
+                _ = sleep(interval) => {}
+                _ = handle.wake.notified() => {}
+            }
+            let polled_at = Instant::now();
+            let snapshot = match self.srvc.get_status().await {
+                Ok(status) => Some(PlayerSnapshot {
+                    polled_at,
+                    ..PlayerSnapshot::from(status)
+                }),
+                Err(e) => {
+                    debug!("could not poll player status: {}", e);
+                    None
+                }
+            };
+            let state = snapshot.as_ref().map(|snapshot| snapshot.state);
+            interval = if handle.waiters.load(Ordering::SeqCst) > 0 || state != last_state {
+                self.settings.fast_interval
+            } else {
+                min(interval * 2, self.settings.slow_interval)
+            };
+            last_state = state;
+            self.srvc.publish(snapshot);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(state: &str, polled_at: Instant) -> Option<PlayerSnapshot> {
+        let status = serde_json::from_value::<VlcStatus>(serde_json::json!({ "state": state }));
+        Some(PlayerSnapshot {
+            polled_at,
+            ..PlayerSnapshot::from(status.unwrap())
+        })
+    }
+
+    #[test]
+    fn stream_of_a_german_vlc_is_read() {
+        // captured from the status.json of a vlc running with LANG=de_DE.UTF-8
+        let status = serde_json::json!({
+            "state": "playing",
+            "time": 42,
+            "volume": 256,
+            "information": {
+                "category": {
+                    "meta": {
+                        "filename": "live",
+                        "title": "Deutschlandfunk",
+                        "now_playing": "Nachrichten"
+                    },
+                    "Stream 0": {
+                        "Bitrate": "128 kB/s",
+                        "Typ": "Audio",
+                        "Kanäle": "Stereo",
+                        "Abtastrate": "44100 Hz",
+                        "Codec": "MPEG Audio layer 1/2 (mpga)",
+                        "Bits_pro_Sample": "32"
+                    }
+                }
+            }
+        });
+        let snapshot = PlayerSnapshot::from(serde_json::from_value::<VlcStatus>(status).unwrap());
+
+        assert_eq!(snapshot.state, PlaybackState::Playing);
+        assert_eq!(
+            snapshot.metadata.now_playing.as_deref(),
+            Some("Nachrichten")
+        );
+        let stream = snapshot.stream.unwrap();
+        assert_eq!(stream.codec.as_deref(), Some("MPEG Audio layer 1/2 (mpga)"));
+        assert_eq!(stream.bitrate.as_deref(), Some("128 kB/s"));
+        assert_eq!(stream.sample_rate.as_deref(), Some("44100 Hz"));
+        assert_eq!(stream.channels.as_deref(), Some("Stereo"));
+    }
+
+    #[tokio::test]
+    async fn wait_until_skips_polls_sent_before() {
+        let (snapshots, handle) = status_channel();
+        let before = Instant::now();
+        let since = before + Duration::from_millis(1);
+        let mut waiting = tokio::spawn(async move {
+            handle
+                .wait_until(since, |snapshot| snapshot.state == PlaybackState::Playing)
+                .await
+        });
+        sleep(Duration::from_millis(10)).await;
+
+        // the answer to a poll that was on its way during the command
+        snapshots.send_replace(snapshot("playing", before));
+        let early = tokio::time::timeout(Duration::from_millis(50), &mut waiting).await;
+        assert!(early.is_err());
+
+        snapshots.send_replace(snapshot("stopped", since));
+        snapshots.send_replace(snapshot("playing", since));
+        let snapshot = waiting.await.unwrap().unwrap();
+        assert_eq!(snapshot.polled_at, since);
+    }
+}
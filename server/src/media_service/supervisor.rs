@@ -0,0 +1,222 @@
+use std::{
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    process::{Child, Command},
+    select,
+    sync::Notify,
+    time::{sleep, timeout},
+};
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+use crate::errors::{ErrorReport, HomeRadioError};
+
+use super::{unix_time, PlayerHandle, RemoteMediaService};
+
+pub struct SupervisorSettings {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// a process that ran at least this long counts as stable and resets the backoff
+    pub stable_after: Duration,
+    pub health_retries: u16,
+    pub health_interval_millis: u16,
+    /// how long vlc gets to quit after SIGTERM before it is killed
+    pub stop_timeout: Duration,
+}
+
+#[derive(Serialize, ToSchema, Clone, Default)]
+pub struct SupervisorReport {
+    pub pid: Option<u32>,
+    /// seconds since the unix epoch
+    pub started_at: Option<u64>,
+    /// seconds the current process has been running
+    pub uptime_secs: Option<u64>,
+    pub restarts: u64,
+    pub last_exit_status: Option<String>,
+    /// seconds since the unix epoch
+    pub last_exit_at: Option<u64>,
+}
+
+#[derive(Clone, Default)]
+pub struct SupervisorHandle {
+    inner: Arc<Mutex<SupervisorReport>>,
+    stopping: Arc<Notify>,
+}
+
+impl SupervisorHandle {
+    /// Makes the supervisor terminate vlc instead of restarting it, `VlcSupervisor::run` returns once it is gone.
+    pub fn stop(&self) {
+        self.stopping.notify_one();
+    }
+
+    pub fn report(&self) -> SupervisorReport {
+        let mut report = self.inner.lock().unwrap().clone();
+        report.uptime_secs = report
+            .started_at
+            .filter(|_| report.pid.is_some())
+            .map(|started_at| unix_time().saturating_sub(started_at));
+        report
+    }
+}
+
+impl SupervisorReport {
+    /// The last exit of vlc, every exit is unexpected.
+    pub fn last_error(&self) -> Option<ErrorReport> {
+        Some(ErrorReport {
+            timestamp: self.last_exit_at?,
+            component: "vlc".into(),
+            message: format!("vlc exited: {}", self.last_exit_status.as_ref()?),
+        })
+    }
+}
+
+/// Keeps the vlc process running, restarting it with backoff and restoring the playback state when it exits.
+pub struct VlcSupervisor {
+    program: String,
+    args: Vec<String>,
+    settings: SupervisorSettings,
+    handle: SupervisorHandle,
+    child: Child,
+}
+
+impl VlcSupervisor {
+    pub fn spawn(
+        program: String,
+        args: Vec<String>,
+        settings: SupervisorSettings,
+    ) -> Result<Self, HomeRadioError> {
+        let handle = SupervisorHandle::default();
+        let child = start(&program, &args, &handle)?;
+        Ok(VlcSupervisor {
+            program,
+            args,
+            settings,
+            handle,
+            child,
+        })
+    }
+
+    pub fn handle(&self) -> SupervisorHandle {
+        self.handle.clone()
+    }
+
+    pub async fn run(mut self, srvc: RemoteMediaService, player: PlayerHandle) {
+        let mut backoff = self.settings.initial_backoff;
+        loop {
+            let started = Instant::now();
+            let status = select! {
+                status = self.child.wait() => status,
+                _ = self.handle.stopping.notified() => return self.terminate().await,
+            };
+            let status = match status {
+                Ok(status) => status.to_string(),
+                Err(e) => e.to_string(),
+            };
+            warn!("vlc exited: {}", &status);
+            {
+                let mut report = self.handle.inner.lock().unwrap();
+                report.pid = None;
+                report.last_exit_status = Some(status);
+                report.last_exit_at = Some(unix_time());
+            }
+            if started.elapsed() >= self.settings.stable_after {
+                backoff = self.settings.initial_backoff;
+            }
+
+            loop {
+                select! {
+                    _ = sleep(backoff) => {}
+                    // nothing is running that would have to be terminated
+                    _ = self.handle.stopping.notified() => return,
+                }
+                backoff = std::cmp::min(backoff * 2, self.settings.max_backoff);
+                match start(&self.program, &self.args, &self.handle) {
+                    Ok(child) => {
+                        self.child = child;
+                        break;
+                    }
+                    Err(e) => error!("could not restart vlc: {}", e),
+                }
+            }
+            self.handle.inner.lock().unwrap().restarts += 1;
+
+            if let Err(e) = restore(&self.settings, &srvc, &player).await {
+                error!("could not restore playback after vlc restart: {}", e);
+            }
+        }
+    }
+
+    /// Asks vlc to quit with SIGTERM and kills it if it doesn't in time. Either way it is reaped.
+    async fn terminate(mut self) {
+        if let Some(pid) = self.child.id() {
+            // SAFETY: kill only sends a signal, the pid is that of our own child that wasn't reaped yet
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+        }
+        let status = match timeout(self.settings.stop_timeout, self.child.wait()).await {
+            Ok(status) => status,
+            Err(_) => {
+                warn!(
+                    "vlc didn't quit within {:?}, killing it",
+                    self.settings.stop_timeout
+                );
+                // waits for the killed process as well
+                self.child.kill().await.and(self.child.wait().await)
+            }
+        };
+        match status {
+            Ok(status) => info!("vlc stopped: {}", status),
+            Err(e) => error!("could not stop vlc: {}", e),
+        }
+        self.handle.inner.lock().unwrap().pid = None;
+    }
+}
+
+fn start(
+    program: &str,
+    args: &[String],
+    handle: &SupervisorHandle,
+) -> Result<Child, HomeRadioError> {
+    info!("starting {}", program);
+    let mut child = Command::new(program)
+        .args(args)
+        // the status.json keys are translated, keep them the way the status poller expects them
+        .env("LC_ALL", "C")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    if let Some(stdout) = child.stdout.take() {
+        actix_web::rt::spawn(forward_output(stdout));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        actix_web::rt::spawn(forward_output(stderr));
+    }
+    let mut report = handle.inner.lock().unwrap();
+    report.pid = child.id();
+    report.started_at = Some(unix_time());
+    Ok(child)
+}
+
+async fn forward_output<R: AsyncRead + Unpin>(output: R) {
+    let mut lines = BufReader::new(output).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        info!(target: "vlc", "{}", line);
+    }
+}
+
+/// Brings a freshly started player back to the volume and source it had before.
+async fn restore(
+    settings: &SupervisorSettings,
+    srvc: &RemoteMediaService,
+    player: &PlayerHandle,
+) -> Result<(), HomeRadioError> {
+    srvc.wait_for_healthy(settings.health_retries, settings.health_interval_millis)
+        .await?;
+    player.restore().await
+}
@@ -0,0 +1,262 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::time::sleep;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{backend::PlaybackCandidate, errors::ErrorReport};
+
+use super::{unix_time, PlayerHandle, RemoteMediaService};
+
+const MAX_INCIDENTS: usize = 20;
+
+pub struct WatchdogSettings {
+    pub poll_interval: Duration,
+    /// number of consecutive polls without progress after which a playing stream counts as stalled
+    pub stall_polls: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+#[derive(Serialize, ToSchema, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum IncidentKind {
+    Stopped,
+    Stalled,
+    RetryFailed,
+    Recovered,
+}
+
+#[derive(Serialize, ToSchema, Clone)]
+pub struct Incident {
+    /// seconds since the unix epoch
+    pub timestamp: u64,
+    pub kind: IncidentKind,
+    pub source: String,
+    pub message: Option<String>,
+}
+
+#[derive(Serialize, ToSchema, Clone, Default)]
+pub struct WatchdogReport {
+    /// the link that is actually playing, which may be an alternate link or a fallback source
+    pub active: Option<PlaybackCandidate>,
+    pub reconnects: u64,
+    pub failed_attempts: u64,
+    pub recovering: bool,
+    #[schema(value_type = Vec<Incident>)]
+    pub incidents: VecDeque<Incident>,
+}
+
+impl WatchdogReport {
+    /// The latest incident that wasn't a recovery.
+    pub fn last_error(&self) -> Option<ErrorReport> {
+        let incident = self
+            .incidents
+            .iter()
+            .rev()
+            .find(|incident| !matches!(incident.kind, IncidentKind::Recovered))?;
+        let mut message = format!("{:?} on {}", incident.kind, incident.source);
+        if let Some(detail) = &incident.message {
+            message.push_str(": ");
+            message.push_str(detail);
+        }
+        Some(ErrorReport {
+            timestamp: incident.timestamp,
+            component: "playback".into(),
+            message,
+        })
+    }
+}
+
+#[derive(Default)]
+struct WatchdogState {
+    armed: bool,
+    generation: u64,
+    report: WatchdogReport,
+}
+
+/// Shared handle used by the player service to tell the watchdog whether playback is supposed to run.
+#[derive(Clone, Default)]
+pub struct WatchdogHandle {
+    inner: Arc<Mutex<WatchdogState>>,
+}
+
+impl WatchdogHandle {
+    /// Starts watching the current media source. Any recovery still in progress for a previous source is abandoned.
+    pub fn arm(&self, active: PlaybackCandidate) {
+        let mut state = self.inner.lock().unwrap();
+        state.armed = true;
+        state.generation += 1;
+        state.report.recovering = false;
+        state.report.active = Some(active);
+    }
+
+    pub fn disarm(&self) {
+        let mut state = self.inner.lock().unwrap();
+        state.armed = false;
+        state.generation += 1;
+        state.report.recovering = false;
+        state.report.active = None;
+    }
+
+    pub fn active(&self) -> Option<PlaybackCandidate> {
+        self.inner.lock().unwrap().report.active.clone()
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.inner.lock().unwrap().armed
+    }
+
+    pub fn report(&self) -> WatchdogReport {
+        self.inner.lock().unwrap().report.clone()
+    }
+
+    pub(super) fn generation(&self) -> Option<u64> {
+        let state = self.inner.lock().unwrap();
+        if state.armed {
+            Some(state.generation)
+        } else {
+            None
+        }
+    }
+
+    fn recovered(&self, generation: u64, active: &PlaybackCandidate) {
+        let mut state = self.inner.lock().unwrap();
+        if state.generation == generation {
+            state.report.active = Some(active.clone());
+        }
+    }
+
+    fn record(&self, kind: IncidentKind, source: &str, message: Option<String>) {
+        match &message {
+            Some(msg) => warn!("watchdog: {:?} for {}: {}", kind, source, msg),
+            None => warn!("watchdog: {:?} for {}", kind, source),
+        }
+        let mut state = self.inner.lock().unwrap();
+        let report = &mut state.report;
+        match kind {
+            IncidentKind::Stopped | IncidentKind::Stalled => report.recovering = true,
+            IncidentKind::RetryFailed => report.failed_attempts += 1,
+            IncidentKind::Recovered => {
+                report.recovering = false;
+                report.reconnects += 1;
+            }
+        }
+        if report.incidents.len() == MAX_INCIDENTS {
+            report.incidents.pop_front();
+        }
+        report.incidents.push_back(Incident {
+            timestamp: unix_time(),
+            kind,
+            source: source.into(),
+            message,
+        });
+    }
+}
+
+/// Watches the player while a source is supposed to be playing and restarts it on unexpected stops or stalls.
+pub struct PlaybackWatchdog {
+    settings: WatchdogSettings,
+    handle: WatchdogHandle,
+    srvc: RemoteMediaService,
+    player: PlayerHandle,
+}
+
+impl PlaybackWatchdog {
+    pub fn new(
+        settings: WatchdogSettings,
+        handle: WatchdogHandle,
+        srvc: RemoteMediaService,
+        player: PlayerHandle,
+    ) -> Self {
+        PlaybackWatchdog {
+            settings,
+            handle,
+            srvc,
+            player,
+        }
+    }
+
+    pub async fn run(self) {
+        let mut last_time = None;
+        let mut frozen_polls = 0;
+        loop {
+            sleep(self.settings.poll_interval).await;
+            let generation = match self.handle.generation() {
+                Some(generation) => generation,
+                None => {
+                    last_time = None;
+                    continue;
+                }
+            };
+            let source = match self.handle.active() {
+                Some(active) => active.link,
+                None => continue,
+            };
+            let status = match self.srvc.get_status().await {
+                Ok(status) => status,
+                Err(e) => {
+                    warn!("watchdog could not query player status: {}", e);
+                    continue;
+                }
+            };
+
+            let incident = match &status.state[..] {
+                "stopped" => Some(IncidentKind::Stopped),
+                "playing" if last_time == Some(status.time) => {
+                    frozen_polls += 1;
+                    if frozen_polls >= self.settings.stall_polls {
+                        Some(IncidentKind::Stalled)
+                    } else {
+                        None
+                    }
+                }
+                _ => {
+                    frozen_polls = 0;
+                    None
+                }
+            };
+            last_time = Some(status.time);
+
+            if let Some(kind) = incident {
+                self.handle.record(kind, &source, None);
+                self.recover(&source, generation).await;
+                last_time = None;
+                frozen_polls = 0;
+            }
+        }
+    }
+
+    async fn recover(&self, source: &str, generation: u64) {
+        let mut backoff = self.settings.initial_backoff;
+        // stop as soon as playback was stopped or switched to another source in the meantime
+        while self.handle.generation() == Some(generation) {
+            // every attempt starts over with the primary link, so a recovery returns to it if it plays again
+            let result = self.player.resume(generation).await;
+            match result {
+                Ok(None) => return,
+                Ok(Some(active)) => {
+                    info!(
+                        "watchdog restarted playback of {} using {}",
+                        source, active.link
+                    );
+                    self.handle.recovered(generation, &active);
+                    self.handle
+                        .record(IncidentKind::Recovered, source, Some(active.link));
+                    return;
+                }
+                Err(e) => {
+                    self.handle
+                        .record(IncidentKind::RetryFailed, source, Some(e.to_string()));
+                }
+            }
+            sleep(backoff).await;
+            backoff = std::cmp::min(backoff * 2, self.settings.max_backoff);
+        }
+    }
+}
@@ -0,0 +1,388 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Write},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::media_service::{PlaybackState, PlayerSnapshot, SupervisorReport, WatchdogReport};
+
+/// upper bounds in seconds of the buckets of the request latency histogram
+const REQUEST_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// upper bounds in seconds of the buckets of the time to first audio histogram
+const FIRST_AUDIO_BUCKETS: &[f64] = &[0.5, 1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 30.0];
+/// label of plays of links that aren't stored as a media source
+const ADHOC_SOURCE: &str = "ad-hoc";
+/// methods that get their own series, any other method a client makes up is counted as `OTHER`
+const METHODS: &[&str] = &["GET", "HEAD", "POST", "PUT", "DELETE", "PATCH", "OPTIONS"];
+const OTHER_METHOD: &str = "OTHER";
+
+struct Histogram {
+    buckets: &'static [f64],
+    /// not cumulative, one more than `buckets` for `+Inf`
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Histogram {
+            buckets,
+            counts: vec![0; buckets.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let bucket = self
+            .buckets
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.buckets.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulative += count;
+            let le = match self.buckets.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".into(),
+            };
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, le, cumulative
+            );
+        }
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, cumulative);
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    /// by route, method and status
+    requests: BTreeMap<(String, String, u16), u64>,
+    /// by route and method
+    request_durations: BTreeMap<(String, String), Histogram>,
+    /// by source and whether the player started
+    plays: BTreeMap<(String, bool), u64>,
+    /// by source
+    first_audio: BTreeMap<String, Histogram>,
+}
+
+/// What is counted while the server runs, exported with the reports of the background tasks at `/metrics`.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Mutex<Registry>>,
+}
+
+impl Metrics {
+    /// `route` is the pattern the request matched, so ids in paths don't create new series.
+    pub fn observe_request(&self, route: &str, method: &str, status: u16, elapsed: Duration) {
+        let method = if METHODS.contains(&method) {
+            method
+        } else {
+            OTHER_METHOD
+        };
+        let mut registry = self.inner.lock().unwrap();
+        *registry
+            .requests
+            .entry((route.into(), method.into(), status))
+            .or_default() += 1;
+        registry
+            .request_durations
+            .entry((route.into(), method.into()))
+            .or_insert_with(|| Histogram::new(REQUEST_BUCKETS))
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Counts an attempt to play a link of `source`, with the time until the player reported playing if it did.
+    pub fn observe_play(&self, source: Option<&str>, first_audio: Option<Duration>) {
+        let source = source.unwrap_or(ADHOC_SOURCE);
+        let mut registry = self.inner.lock().unwrap();
+        *registry
+            .plays
+            .entry((source.into(), first_audio.is_some()))
+            .or_default() += 1;
+        if let Some(first_audio) = first_audio {
+            registry
+                .first_audio
+                .entry(source.into())
+                .or_insert_with(|| Histogram::new(FIRST_AUDIO_BUCKETS))
+                .observe(first_audio.as_secs_f64());
+        }
+    }
+
+    /// Everything in the Prometheus text format.
+    pub fn render(
+        &self,
+        player: Option<&PlayerSnapshot>,
+        watchdog: &WatchdogReport,
+        vlc: &SupervisorReport,
+        storage_errors: u64,
+    ) -> String {
+        let mut out = String::new();
+        {
+            let registry = self.inner.lock().unwrap();
+            header(
+                &mut out,
+                "home_radio_http_requests_total",
+                "counter",
+                "http requests by route, method and status",
+            );
+            for ((route, method, status), count) in &registry.requests {
+                let _ = writeln!(
+                    out,
+                    "home_radio_http_requests_total{{route=\"{}\",method=\"{}\",status=\"{}\"}} {}",
+                    escape(route),
+                    method,
+                    status,
+                    count
+                );
+            }
+            header(
+                &mut out,
+                "home_radio_http_request_duration_seconds",
+                "histogram",
+                "time until the response headers were ready",
+            );
+            for ((route, method), histogram) in &registry.request_durations {
+                let labels = format!("route=\"{}\",method=\"{}\"", escape(route), method);
+                histogram.render(
+                    &mut out,
+                    "home_radio_http_request_duration_seconds",
+                    &labels,
+                );
+            }
+            header(
+                &mut out,
+                "home_radio_play_attempts_total",
+                "counter",
+                "links handed to the player by media source and whether it started playing",
+            );
+            for ((source, started), count) in &registry.plays {
+                let result = if *started { "playing" } else { "failed" };
+                let _ = writeln!(
+                    out,
+                    "home_radio_play_attempts_total{{source=\"{}\",result=\"{}\"}} {}",
+                    escape(source),
+                    result,
+                    count
+                );
+            }
+            header(
+                &mut out,
+                "home_radio_time_to_first_audio_seconds",
+                "histogram",
+                "time from handing a link to the player until it reported playing",
+            );
+            for (source, histogram) in &registry.first_audio {
+                let labels = format!("source=\"{}\"", escape(source));
+                histogram.render(&mut out, "home_radio_time_to_first_audio_seconds", &labels);
+            }
+        }
+
+        single(
+            &mut out,
+            "home_radio_player_up",
+            "gauge",
+            "whether the player answered the last poll",
+            player.is_some() as u8,
+        );
+        if let Some(player) = player {
+            header(
+                &mut out,
+                "home_radio_player_state",
+                "gauge",
+                "1 for the state the player reports",
+            );
+            for (state, name) in [
+                (PlaybackState::Playing, "playing"),
+                (PlaybackState::Paused, "paused"),
+                (PlaybackState::Stopped, "stopped"),
+                (PlaybackState::Unknown, "unknown"),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "home_radio_player_state{{state=\"{}\"}} {}",
+                    name,
+                    (player.state == state) as u8
+                );
+            }
+            single(
+                &mut out,
+                "home_radio_volume",
+                "gauge",
+                "volume in the units of the player, 256 is 100%",
+                player.volume,
+            );
+            let bitrate = player
+                .stream
+                .as_ref()
+                .and_then(|stream| stream.bitrate.as_deref())
+                .and_then(bits_per_second);
+            if let Some(bitrate) = bitrate {
+                single(
+                    &mut out,
+                    "home_radio_stream_bitrate_bits_per_second",
+                    "gauge",
+                    "bitrate of the stream as reported by the player",
+                    bitrate,
+                );
+            }
+        }
+
+        single(
+            &mut out,
+            "home_radio_reconnects_total",
+            "counter",
+            "playback resumed by the watchdog after a stop or stall",
+            watchdog.reconnects,
+        );
+        single(
+            &mut out,
+            "home_radio_reconnect_failures_total",
+            "counter",
+            "attempts of the watchdog to resume playback that failed",
+            watchdog.failed_attempts,
+        );
+        single(
+            &mut out,
+            "home_radio_vlc_restarts_total",
+            "counter",
+            "restarts of the spawned vlc",
+            vlc.restarts,
+        );
+        single(
+            &mut out,
+            "home_radio_storage_errors_total",
+            "counter",
+            "failed reads and writes of the state",
+            storage_errors,
+        );
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// A metric without labels.
+fn single(out: &mut String, name: &str, kind: &str, help: &str, value: impl Display) {
+    header(out, name, kind, help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Parses what the player reports, e.g. `128 kb/s`, or `1,5 Mbit/s` from a vlc with a german locale.
+fn bits_per_second(bitrate: &str) -> Option<f64> {
+    let mut parts = bitrate.split_whitespace();
+    let value: f64 = parts.next()?.replace(',', ".").parse().ok()?;
+    // the translations differ in the case of the unit
+    let factor = match parts.next().map(str::to_ascii_lowercase).as_deref() {
+        Some("kb/s") | Some("kbit/s") => 1_000.0,
+        Some("mb/s") | Some("mbit/s") => 1_000_000.0,
+        Some("b/s") | Some("bit/s") | None => 1.0,
+        Some(_) => return None,
+    };
+    Some(value * factor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media_service::VlcStatus;
+
+    #[test]
+    fn bitrates_of_the_player_are_parsed() {
+        assert_eq!(bits_per_second("128 kb/s"), Some(128_000.0));
+        assert_eq!(bits_per_second("1.5 Mb/s"), Some(1_500_000.0));
+        assert_eq!(bits_per_second("800"), Some(800.0));
+        // a vlc with a german locale
+        assert_eq!(bits_per_second("1,5 Mbit/s"), Some(1_500_000.0));
+        assert_eq!(bits_per_second("128 kBit/s"), Some(128_000.0));
+        assert_eq!(bits_per_second("128 kB/s"), Some(128_000.0));
+        assert_eq!(bits_per_second("128 frames/s"), None);
+        assert_eq!(bits_per_second("unbekannt"), None);
+    }
+
+    #[test]
+    fn histogram_counts_cumulative_buckets() {
+        let mut histogram = Histogram::new(&[0.1, 1.0]);
+        for value in [0.05, 0.1, 0.5, 3.0] {
+            histogram.observe(value);
+        }
+        let mut out = String::new();
+        histogram.render(&mut out, "latency", "route=\"/\"");
+
+        assert_eq!(
+            out,
+            "latency_bucket{route=\"/\",le=\"0.1\"} 2\n\
+             latency_bucket{route=\"/\",le=\"1\"} 3\n\
+             latency_bucket{route=\"/\",le=\"+Inf\"} 4\n\
+             latency_sum{route=\"/\"} 3.65\n\
+             latency_count{route=\"/\"} 4\n"
+        );
+    }
+
+    #[test]
+    fn render_exports_requests_plays_and_the_player() {
+        let metrics = Metrics::default();
+        let elapsed = Duration::from_millis(20);
+        metrics.observe_request("/api/v1/sources", "GET", 200, elapsed);
+        metrics.observe_request("/api/v1/sources", "GET", 200, elapsed);
+        metrics.observe_request("/api/v1/sources", "BREW", 404, elapsed);
+        metrics.observe_play(Some("Deutschlandfunk"), Some(Duration::from_millis(1500)));
+        metrics.observe_play(None, None);
+        let status = serde_json::json!({
+            "state": "playing",
+            "volume": 256,
+            "information": {
+                "category": {
+                    "Stream 0": { "Typ": "Audio", "Bitrate": "128 kb/s" }
+                }
+            }
+        });
+        let player = PlayerSnapshot::from(serde_json::from_value::<VlcStatus>(status).unwrap());
+
+        let out = metrics.render(
+            Some(&player),
+            &WatchdogReport::default(),
+            &SupervisorReport::default(),
+            3,
+        );
+
+        for line in [
+            "# TYPE home_radio_http_requests_total counter",
+            "home_radio_http_requests_total{route=\"/api/v1/sources\",method=\"GET\",status=\"200\"} 2",
+            "home_radio_http_requests_total{route=\"/api/v1/sources\",method=\"OTHER\",status=\"404\"} 1",
+            "home_radio_http_request_duration_seconds_count{route=\"/api/v1/sources\",method=\"GET\"} 2",
+            "home_radio_play_attempts_total{source=\"Deutschlandfunk\",result=\"playing\"} 1",
+            "home_radio_play_attempts_total{source=\"ad-hoc\",result=\"failed\"} 1",
+            "home_radio_time_to_first_audio_seconds_bucket{source=\"Deutschlandfunk\",le=\"2\"} 1",
+            "home_radio_player_up 1",
+            "home_radio_player_state{state=\"playing\"} 1",
+            "home_radio_player_state{state=\"stopped\"} 0",
+            "home_radio_volume 256",
+            "home_radio_stream_bitrate_bits_per_second 128000",
+            "home_radio_storage_errors_total 3",
+        ] {
+            assert!(out.lines().any(|l| l == line), "missing {}", line);
+        }
+        assert!(!out.contains("BREW"));
+    }
+}
@@ -0,0 +1,45 @@
+//! Fixtures shared by the tests.
+
+use std::path::{Path, PathBuf};
+
+use crate::backend::{MediaSource, MediaType};
+
+/// A directory below the system temp dir that is removed when dropped, also when a test panics.
+/// It isn't created, so tests can check what creates it.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        TempDir(std::env::temp_dir().join(format!("home-radio-test-{}", rand::random::<u64>())))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A radio source without id, alternates or fallback.
+pub fn source(name: &str, link: &str) -> MediaSource {
+    MediaSource {
+        id: 0,
+        link: link.into(),
+        name: name.into(),
+        media_type: MediaType::Radio,
+        currently_playing: None,
+        default_source: false,
+        alternate_links: Vec::new(),
+        fallback_source: None,
+        active_link: None,
+        active_source: None,
+    }
+}
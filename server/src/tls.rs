@@ -0,0 +1,168 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use rcgen::{Certificate, CertificateParams, SanType};
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    PrivateKey, ServerConfig,
+};
+use rustls_pemfile::Item;
+use tokio::{fs, time::interval};
+use tracing::{error, info, warn};
+
+use crate::{backend::write_atomic, errors::HomeRadioError};
+
+pub struct TlsSettings {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// generate a certificate for `hostnames` if neither `cert` nor `key` exist
+    pub self_signed: bool,
+    pub hostnames: Vec<String>,
+    pub reload_interval: Duration,
+}
+
+/// Hands out the current certificate to new connections, so it can be replaced while the server runs.
+pub struct CertStore {
+    settings: TlsSettings,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertStore {
+    /// Loads the certificate and key, generating a self-signed pair first if allowed.
+    pub async fn new(settings: TlsSettings) -> Result<Arc<Self>, HomeRadioError> {
+        match (settings.cert.exists(), settings.key.exists()) {
+            (true, true) => {}
+            (false, false) if settings.self_signed => generate_self_signed(&settings).await?,
+            _ => {
+                return Err(HomeRadioError::Tls(format!(
+                    "{} and {} must both exist",
+                    settings.cert.display(),
+                    settings.key.display()
+                )))
+            }
+        }
+        let key = load(&settings.cert, &settings.key)?;
+        info!("serving https with {}", settings.cert.display());
+        Ok(Arc::new(CertStore {
+            settings,
+            current: RwLock::new(Arc::new(key)),
+        }))
+    }
+
+    pub fn server_config(self: &Arc<Self>) -> ServerConfig {
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone())
+    }
+
+    /// Picks up renewed certificates. A pair that can't be loaded is logged and the previous one is kept.
+    pub async fn run(self: Arc<Self>) {
+        let mut last = self.modified();
+        let mut ticks = interval(self.settings.reload_interval);
+        loop {
+            ticks.tick().await;
+            let modified = self.modified();
+            if modified == last {
+                continue;
+            }
+            last = modified;
+            match load(&self.settings.cert, &self.settings.key) {
+                Ok(key) => {
+                    *self.current.write().unwrap() = Arc::new(key);
+                    info!("reloaded the certificate {}", self.settings.cert.display());
+                }
+                Err(e) => error!("keeping the previous certificate: {}", e),
+            }
+        }
+    }
+
+    fn modified(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        (modified(&self.settings.cert), modified(&self.settings.key))
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Reads a pem certificate chain and the first private key in the key file.
+fn load(cert: &Path, key: &Path) -> Result<CertifiedKey, HomeRadioError> {
+    let chain: Vec<rustls::Certificate> = read_pem(cert)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(rustls::Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    if chain.is_empty() {
+        return Err(HomeRadioError::Tls(format!(
+            "{} contains no certificate",
+            cert.display()
+        )));
+    }
+    let der = read_pem(key)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) => Some(der),
+            _ => None,
+        })
+        .ok_or_else(|| HomeRadioError::Tls(format!("{} contains no private key", key.display())))?;
+    let signing_key = sign::any_supported_type(&PrivateKey(der))
+        .map_err(|e| HomeRadioError::Tls(format!("{}: {}", key.display(), e)))?;
+    Ok(CertifiedKey::new(chain, signing_key))
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>, HomeRadioError> {
+    let file = File::open(path).map_err(|e| tls_io_error(path, e))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file)).map_err(|e| tls_io_error(path, e))
+}
+
+fn tls_io_error(path: &Path, e: io::Error) -> HomeRadioError {
+    HomeRadioError::Tls(format!("could not read {}: {}", path.display(), e))
+}
+
+async fn generate_self_signed(settings: &TlsSettings) -> Result<(), HomeRadioError> {
+    let mut params = CertificateParams::default();
+    params.subject_alt_names = settings
+        .hostnames
+        .iter()
+        .map(|name| match name.parse::<IpAddr>() {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(name.clone()),
+        })
+        .collect();
+    let cert = Certificate::from_params(params).map_err(|e| HomeRadioError::Tls(e.to_string()))?;
+    let cert_pem = cert
+        .serialize_pem()
+        .map_err(|e| HomeRadioError::Tls(e.to_string()))?;
+
+    for path in [&settings.cert, &settings.key] {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+    }
+    write_atomic(
+        &settings.key,
+        cert.serialize_private_key_pem().as_bytes(),
+        0o600,
+    )
+    .await?;
+    write_atomic(&settings.cert, cert_pem.as_bytes(), 0o644).await?;
+    warn!(
+        "generated a self-signed certificate for {} at {}, browsers will ask to trust it",
+        settings.hostnames.join(", "),
+        settings.cert.display()
+    );
+    Ok(())
+}
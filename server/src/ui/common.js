@@ -23,10 +23,14 @@ function makeRequest(method, url, body, headers = []) {
         xhr.onload = function () {
             if (this.status >= 200 && this.status < 300) {
                 resolve(xhr.response);
+            } else if (this.status == 401 && url != "/api/v1/login") {
+                // authentication is enabled and the session is missing or expired
+                window.location.href = "/login.html";
             } else {
                 reject({
                     status: this.status,
-                    statusText: xhr.statusText
+                    statusText: xhr.statusText,
+                    body: xhr.response
                 });
             }
         };
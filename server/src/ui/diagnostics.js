@@ -0,0 +1,51 @@
+// how often the records are fetched again while the page is open
+const REFRESH_MILLIS = 5000;
+
+window.addEventListener("load", function () {
+    loadLogs();
+    setInterval(loadLogs, REFRESH_MILLIS);
+});
+
+async function loadLogs() {
+    let level = document.getElementById("level").value;
+    let period = document.getElementById("period").value;
+    let error = document.getElementById("error");
+    let query = "level=" + level;
+    if (period) {
+        query += "&since=" + (Math.floor(Date.now() / 1000) - period);
+    }
+
+    let records;
+    try {
+        records = JSON.parse(await get("/logs?" + query));
+        error.textContent = "";
+    } catch (e) {
+        console.log(e);
+        error.textContent = e.status == 403
+            ? "Keine Berechtigung, die Protokolle zu lesen"
+            : "Protokolle konnten nicht geladen werden";
+        return;
+    }
+
+    let body = document.getElementById("records");
+    body.replaceChildren();
+    // newest first
+    records.reverse().forEach(function (record) {
+        let row = document.createElement("tr");
+        row.className = record.level;
+        let message = record.spans.length > 0
+            ? record.spans.join(": ") + ": " + record.message
+            : record.message;
+        [
+            new Date(record.timestamp * 1000).toLocaleString(),
+            record.level,
+            record.target,
+            message,
+        ].forEach(function (text) {
+            let cell = document.createElement("td");
+            cell.appendChild(document.createTextNode(text));
+            row.appendChild(cell);
+        });
+        body.appendChild(row);
+    });
+}
@@ -1,17 +1,34 @@
 var isPlaying = false;
 
 window.addEventListener("load", async function () {
+    await loadMedia();
+
+    result = await get("/volume");
+    console.log("setting volume to", result);
+    volumeSlider = document.getElementById("volume");
+    volumeSlider.value = result;
+    volumeSlider.addEventListener('change', async function () {
+        await put("/volume", volumeSlider.value);
+    });
+
+    subscribe();
+});
+
+async function loadMedia() {
     console.log("loading media");
     result = await get("/media");
     media = JSON.parse(result);
     let default_source = null;
     let currently_playing = null;
+    let select = document.getElementById("radio_links");
+    select.replaceChildren();
+    isPlaying = false;
     media.forEach(function (item) {
         console.log(item);
         element = document.createElement("option");
-        element.value = item.link;
+        element.value = item.id;
         element.appendChild(document.createTextNode(item.name));
-        document.getElementById("radio_links").appendChild(element);
+        select.appendChild(element);
         if (item.default_source) {
             default_source = item;
             if (currently_playing == null) {
@@ -22,18 +39,40 @@ window.addEventListener("load", async function () {
             currently_playing = item;
             element.selected = true;
             isPlaying = true;
-            switchButtonState(isPlaying);
         }
     });
+    switchButtonState(isPlaying);
+}
 
-    result = await get("/volume");
-    console.log("setting volume to", result);
-    volumeSlider = document.getElementById("volume");
-    volumeSlider.value = result;
-    volumeSlider.addEventListener('change', async function () {
-        await put("/volume", volumeSlider.value);
+// keeps the page in sync with changes made by other clients and by the server itself
+function subscribe() {
+    let events = new EventSource("/events");
+    events.addEventListener("playback", function (message) {
+        let event = JSON.parse(message.data);
+        isPlaying = event.current != null;
+        switchButtonState(isPlaying);
+        // ad-hoc links are not in the list
+        if (event.current != null && event.current.source != null) {
+            document.getElementById("radio_links").value = event.current.source;
+        }
     });
-});
+    events.addEventListener("volume", function (message) {
+        document.getElementById("volume").value = JSON.parse(message.data).volume;
+    });
+    events.addEventListener("sources", function () {
+        loadMedia();
+    });
+    events.addEventListener("now_playing", function (message) {
+        let metadata = JSON.parse(message.data).metadata;
+        let text = metadata.now_playing || metadata.title || "";
+        document.getElementById("now_playing").textContent = text;
+    });
+    events.addEventListener("player_state", function (message) {
+        if (JSON.parse(message.data).state != "playing") {
+            document.getElementById("now_playing").textContent = "";
+        }
+    });
+}
 
 
 async function handleMedia() {
@@ -51,12 +90,33 @@ function switchButtonState(isPlaying) {
 
 async function start() {
     radioUrlsSelect = document.getElementById("radio_links");
-    url = radioUrlsSelect.options[radioUrlsSelect.selectedIndex].value;
-    await post("/start", url);
+    let id = Number(radioUrlsSelect.options[radioUrlsSelect.selectedIndex].value);
+    showError(null);
+    try {
+        await post("/api/v1/playback/start", JSON.stringify({ id: id }), [{ name: "Content-Type", value: "application/json" }]);
+    } catch (error) {
+        showError(error);
+        return;
+    }
     isPlaying = true;
     switchButtonState(isPlaying);
 }
 
+function showError(error) {
+    let element = document.getElementById("error");
+    if (error == null) {
+        element.textContent = "";
+        return;
+    }
+    let message = error.statusText;
+    try {
+        message = JSON.parse(error.body).message;
+    } catch (e) {
+        // body is not a structured error
+    }
+    element.textContent = message;
+}
+
 
 async function stop() {
     await post("/stop");
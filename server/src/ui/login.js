@@ -0,0 +1,15 @@
+async function login() {
+    let user = document.getElementById("user").value;
+    let passwordInput = document.getElementById("password");
+    let password = passwordInput.value;
+    let error = document.getElementById("error");
+
+    try {
+        await post("/api/v1/login", JSON.stringify({ user, password }), [{ name: "Content-Type", value: "application/json" }]);
+        window.location.href = "/";
+    } catch (e) {
+        console.log(e);
+        passwordInput.value = "";
+        error.textContent = "Anmeldung fehlgeschlagen";
+    }
+}